@@ -22,6 +22,20 @@ germinate myfile.txt.tmpl
 germinate myfile.txt.tmpl -o myfile.txt
 ```
 
+Values are written as they are by default. Use `--format` to escape them for the format of the output, e.g. `--format json`, or pass `--detect-format` to detect it from the extension of the output file, or the input file with any `.tmpl` or `.tpl` extension removed.
+
+Pass `--structured` to parse a JSON, YAML or TOML template as a document and only replace placeholders inside it's values, so loaded values never need escaping and a value which is entirely a placeholder keeps the type of the loaded value.
+
+Templates are streamed from the input file, so they never need to fit in memory. When writing to an output file, the output is only moved into place once the template has rendered successfully. When writing to `stdout`, output stops at the first failure, so only what was rendered before it is written.
+
+Pass `--offline-cache <FILE>` to store the loaded values in a file encrypted with `--offline-cache-key` (or `GERMINATE_OFFLINE_CACHE_KEY`), which must be a random 32 byte key written as hex or base64, e.g. the output of `openssl rand -hex 32`. If a source can't be reached on a later run, the cached values are used as long as they're no older than `--offline-cache-max-staleness` seconds (a day by default), and each stale value used is printed as a warning.
+
+Requests which time out, are throttled or fail with a server error are retried with an exponential backoff. Use `--timeout` to set how many seconds each attempt can take (30 by default), `--max-attempts` to set how many times to try (3 by default) and `--deadline` to limit how long rendering the whole template can take.
+
+To avoid being throttled when many hosts render templates at once, `--rate-limit <SOURCE>=<PER_SECOND>[:<BURST>]` limits how many values are loaded from a source each second, after an initial burst which defaults to the same number. It can be given more than once, e.g. `--rate-limit awsssm=10 --rate-limit awss3=5:20`. Placeholders are loaded one after another, so the limit doesn't speed anything up, it only spreads out the requests once the burst is used up.
+
+If any values fail to load, every failure is printed to `stderr` and the CLI exits with a non-zero status. Pass `--fail-fast` to stop at the first failure instead.
+
 ## License
 
 [GPL-3.0](https://github.com/itmecho/germinate/blob/master/LICENSE)
@@ -0,0 +1,117 @@
+//! Reads and writes the structured documents rendered by `Seed::germinate_structured`
+//!
+//! Documents are parsed into a JSON value whatever their format, so placeholders can be resolved
+//! in the same way for each of them. The order of keys is kept when the document is written back
+//! out, but comments and formatting aren't
+use crate::format::Format;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+/// Parses the document in the given format
+pub(crate) fn parse(document: &str, format: Format) -> Result<Value> {
+    let value = match format {
+        Format::Json => serde_json::from_str(document).context("Failed to parse JSON document")?,
+        Format::Yaml => serde_yaml::from_str(document).context("Failed to parse YAML document")?,
+        Format::Toml => {
+            let value: toml::Value =
+                toml::from_str(document).context("Failed to parse TOML document")?;
+            from_toml(value)
+        }
+        other => bail!(
+            "Structured rendering isn't supported for {:?} documents",
+            other
+        ),
+    };
+
+    Ok(value)
+}
+
+/// Writes the document out in the given format
+pub(crate) fn write(value: &Value, format: Format) -> Result<String> {
+    let output = match format {
+        Format::Json => serde_json::to_string_pretty(value)? + "\n",
+        Format::Yaml => serde_yaml::to_string(value)?,
+        Format::Toml => {
+            let value = toml::Value::try_from(value)
+                .context("Document can't be written as TOML, it may contain a null value")?;
+            toml::to_string(&value)?
+        }
+        other => bail!(
+            "Structured rendering isn't supported for {:?} documents",
+            other
+        ),
+    };
+
+    Ok(output)
+}
+
+/// Returns every string scalar in the document, in the order they appear. Keys aren't included,
+/// so placeholders are only resolved in values
+pub(crate) fn strings(value: &mut Value) -> Vec<&mut Value> {
+    match value {
+        Value::String(_) => vec![value],
+        Value::Array(items) => items.iter_mut().flat_map(strings).collect(),
+        Value::Object(map) => map.values_mut().flat_map(strings).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Converts a TOML value to JSON. Dates and times are converted to strings, as JSON has no
+/// equivalent for them
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => {
+            Value::Object(table.into_iter().map(|(k, v)| (k, from_toml(v))).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_and_write() {
+        let yaml = "name: app\nports:\n  - 80\n  - 443\n";
+        let value = parse(yaml, Format::Yaml).unwrap();
+        assert_eq!(json!({"name": "app", "ports": [80, 443]}), value);
+        assert_eq!(
+            "---\nname: app\nports:\n  - 80\n  - 443\n",
+            write(&value, Format::Yaml).unwrap()
+        );
+
+        let toml = "name = \"app\"\n\n[db]\nport = 5432\n";
+        let value = parse(toml, Format::Toml).unwrap();
+        assert_eq!(json!({"name": "app", "db": {"port": 5432}}), value);
+        assert_eq!(toml, write(&value, Format::Toml).unwrap());
+
+        // Keys keep the order they were written in
+        let value = parse(r#"{"b": 1, "a": 2}"#, Format::Json).unwrap();
+        assert_eq!(
+            "{\n  \"b\": 1,\n  \"a\": 2\n}\n",
+            write(&value, Format::Json).unwrap()
+        );
+
+        assert!(parse("a: b", Format::Text).is_err());
+        assert!(parse("{", Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut value = json!({"a": "one", "b": [1, "two", {"c": "three"}], "d": true});
+
+        let found = strings(&mut value)
+            .into_iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["one", "two", "three"], found);
+    }
+}
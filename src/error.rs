@@ -0,0 +1,238 @@
+//! Error types describing why a template failed to render
+use std::fmt;
+use std::time::SystemTime;
+
+/// The error a [`Loader`](trait.Loader.html) returns when the value it was asked for doesn't
+/// exist, as opposed to failing to load it. Optional placeholders render as an empty string when
+/// their loader returns this error
+///
+/// # Example
+/// ```
+/// use germinate::{Loader, NotFound};
+///
+/// struct LanguageLoader {}
+///
+/// #[async_trait::async_trait]
+/// impl Loader for LanguageLoader {
+///     async fn load(&self, key: &str) -> anyhow::Result<String> {
+///         match key {
+///             "go" => Ok("Go".into()),
+///             "rust" => Ok("Rust".into()),
+///             _ => Err(NotFound::new(format!("Unknown language '{}'", key)).into()),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct NotFound {
+    message: String,
+}
+
+impl NotFound {
+    /// Creates a new NotFound error with a message describing what was missing
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+
+    /// Returns true if the error, or any of its causes, is a `NotFound` error
+    pub(crate) fn is(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| e.is::<Self>())
+    }
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// The error a [`Loader`](trait.Loader.html) returns when loading failed for a reason which might
+/// not happen again, such as being throttled or a connection failing. Only failures with this
+/// error, or which time out, are retried by the source's
+/// [`RetryPolicy`](struct.RetryPolicy.html)
+///
+/// # Example
+/// ```
+/// use germinate::{Loader, Retryable};
+///
+/// struct FlakyLoader {}
+///
+/// #[async_trait::async_trait]
+/// impl Loader for FlakyLoader {
+///     async fn load(&self, key: &str) -> anyhow::Result<String> {
+///         Err(Retryable::new("Service is busy").into())
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Retryable {
+    message: String,
+}
+
+impl Retryable {
+    /// Creates a new Retryable error with a message describing what failed
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+
+    /// Returns true if the error, or any of its causes, is a `Retryable` error
+    pub(crate) fn is(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| e.is::<Self>())
+    }
+}
+
+impl fmt::Display for Retryable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Retryable {}
+
+/// A single failure encountered while rendering a template
+#[derive(Debug)]
+pub enum Failure {
+    /// The loader for a source failed to initialise, so none of the placeholders using it could be
+    /// loaded
+    Init {
+        /// The template key of the source, e.g. `awsssm`
+        source: String,
+        /// Why the loader failed to initialise
+        error: anyhow::Error,
+    },
+    /// The value for a placeholder couldn't be loaded
+    Load {
+        /// The placeholder as it appears in the template, e.g. `%env:DB_HOST%`
+        placeholder: String,
+        /// The template key of the source, e.g. `env`
+        source: String,
+        /// The key passed to the loader, e.g. `DB_HOST`, with the values of any secrets it was built
+        /// from masked
+        key: String,
+        /// Why the value couldn't be loaded
+        error: anyhow::Error,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Init { source, error } => {
+                write!(
+                    f,
+                    "Failed to initialise the '{}' source: {:#}",
+                    source, error
+                )
+            }
+            Self::Load {
+                placeholder,
+                source,
+                key,
+                error,
+            } => write!(
+                f,
+                "Failed to load {} (source '{}', key '{}'): {:#}",
+                placeholder, source, key, error
+            ),
+        }
+    }
+}
+
+/// A value which couldn't be loaded, so the value stored in the offline cache was used instead
+#[derive(Debug)]
+pub struct StaleValue {
+    /// The template key of the source, e.g. `awsssm`
+    pub source: String,
+    /// The key passed to the loader, e.g. `/app/db/host`
+    pub key: String,
+    /// When the cached value was loaded
+    pub loaded_at: SystemTime,
+    /// Why the value couldn't be loaded
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for StaleValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let age = SystemTime::now()
+            .duration_since(self.loaded_at)
+            .unwrap_or_default();
+
+        write!(
+            f,
+            "Used a stale value (source '{}', key '{}') loaded {}s ago: {:#}",
+            self.source,
+            self.key,
+            age.as_secs(),
+            self.error
+        )
+    }
+}
+
+/// A report of every failure encountered while rendering a template. This is returned as the
+/// error from rendering when the `Seed` is configured to keep going after a failure, and can be
+/// retrieved from the `anyhow::Error` using `downcast_ref`. The report of a successful render is
+/// available from [`Seed::report`](struct.Seed.html#method.report)
+#[derive(Debug, Default)]
+pub struct Report {
+    failures: Vec<Failure>,
+    stale: Vec<StaleValue>,
+}
+
+impl Report {
+    /// The failures in the order they were encountered
+    pub fn failures(&self) -> &[Failure] {
+        &self.failures
+    }
+
+    /// The values which were used from the offline cache, in the order they were loaded
+    pub fn stale(&self) -> &[StaleValue] {
+        &self.stale
+    }
+
+    pub(crate) fn push(&mut self, failure: Failure) {
+        self.failures.push(failure);
+    }
+
+    pub(crate) fn push_stale(&mut self, stale: StaleValue) {
+        self.stale.push(stale);
+    }
+
+    pub(crate) fn has_failures(&self) -> bool {
+        !self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.failures.len() {
+            0 => write!(
+                f,
+                "Rendered template using {} stale value(s)",
+                self.stale.len()
+            )?,
+            failures => write!(
+                f,
+                "Failed to render template, {} failure(s) found",
+                failures
+            )?,
+        }
+
+        for failure in &self.failures {
+            write!(f, "\n  - {}", failure)?;
+        }
+
+        for stale in &self.stale {
+            write!(f, "\n  - {}", stale)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Report {}
@@ -0,0 +1,878 @@
+//! Escapes loaded values for the format of the file being rendered
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The format of the output being rendered, which controls how loaded values are escaped when
+/// they're written into the template. Values are escaped for where they're written, so a value
+/// inside a quoted string is escaped for that string. In JSON, YAML and TOML, a value which is
+/// the whole of an unquoted value, e.g. `port: %env:PORT%`, is written as a number or boolean if
+/// it is one and as a quoted string otherwise, while a value inside a plain or block scalar, e.g.
+/// `image: app:%env:TAG%`, is written as it is. Each line of a value in a YAML block scalar is
+/// indented to match the line it's written on, and a value with a line break can't be written
+/// into a plain scalar or a comment, or contain a line which would end a shell heredoc
+///
+/// Add `|raw` to the end of a placeholder or variable to write the value as it is, e.g.
+/// `%env:PORT|raw%`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// Values are written as they are
+    #[default]
+    Text,
+    /// Values are escaped as JSON strings
+    Json,
+    /// Values are escaped as YAML double or single quoted strings
+    Yaml,
+    /// Values are escaped for POSIX shell scripts, including inside heredocs, so they're never
+    /// expanded or run
+    Shell,
+    /// Values are escaped using XML character entities
+    Xml,
+    /// Values are escaped as TOML strings. A value can't be written into a literal string if it
+    /// contains a `'`
+    Toml,
+}
+
+impl Format {
+    /// Detects the format from the extension of a file, ignoring a `.tmpl` or `.tpl` extension,
+    /// e.g. `config.json.tmpl` is JSON. Unknown extensions are treated as text
+    ///
+    /// # Example
+    /// ```
+    /// use germinate::Format;
+    ///
+    /// assert_eq!(Format::Yaml, Format::from_path("deployment.yaml.tmpl"));
+    /// assert_eq!(Format::Text, Format::from_path("nginx.conf"));
+    /// ```
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let path = match path.extension().and_then(|e| e.to_str()) {
+            Some("tmpl") | Some("tpl") => Path::new(path.file_stem().unwrap_or_default()),
+            _ => path,
+        };
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::Json,
+            Some("yaml") | Some("yml") => Self::Yaml,
+            Some("sh") | Some("bash") | Some("zsh") | Some("env") => Self::Shell,
+            Some("xml") | Some("html") | Some("svg") => Self::Xml,
+            Some("toml") => Self::Toml,
+            _ => Self::Text,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "yaml" => Ok(Self::Yaml),
+            "shell" => Ok(Self::Shell),
+            "xml" => Ok(Self::Xml),
+            "toml" => Ok(Self::Toml),
+            _ => Err(anyhow!("Invalid output format '{}'", s)),
+        }
+    }
+}
+
+/// How many bytes of the current line are kept to work out where a value is being written
+const TAIL_LEN: usize = 256;
+
+/// Tracks where the end of the output written so far is, such as inside a quoted string, a YAML
+/// block scalar or a shell heredoc, so values can be escaped for where they're written. The output
+/// is pushed to it as it's written, so strings and blocks which span lines are followed, even
+/// when the output is streamed
+#[derive(Clone, Debug)]
+pub(crate) struct Escaper {
+    format: Format,
+    quote: Quote,
+    block: Option<Block>,
+    /// A heredoc started on the current line, which is read from the next line
+    heredoc: Option<Block>,
+    /// Whether the last character was a backslash escaping the next one
+    escaping: bool,
+    /// Whether the rest of the current line is a comment
+    comment: bool,
+    /// The last character written
+    previous: char,
+    /// The end of the current line, with any comment left out
+    tail: String,
+    /// Whether the current line was too long to keep all of it in the tail
+    truncated: bool,
+    /// How much whitespace the current line starts with
+    indent: usize,
+    /// Whether the current line only has whitespace so far
+    blank: bool,
+    /// How many of the next characters are part of a `"""` or `'''` which has been read
+    skip: usize,
+}
+
+impl Escaper {
+    pub(crate) fn new(format: Format) -> Self {
+        Self {
+            format,
+            quote: Quote::None,
+            block: None,
+            heredoc: None,
+            escaping: false,
+            comment: false,
+            previous: '\n',
+            tail: String::new(),
+            truncated: false,
+            indent: 0,
+            blank: true,
+            skip: 0,
+        }
+    }
+
+    /// Follows text which has been written to the output
+    pub(crate) fn push(&mut self, text: &str) {
+        if matches!(self.format, Format::Text | Format::Xml) {
+            return;
+        }
+
+        for (i, c) in text.char_indices() {
+            if c == '\n' {
+                self.end_line();
+                continue;
+            }
+
+            if self.blank && matches!(c, ' ' | '\t') {
+                self.indent += 1;
+            } else if self.blank {
+                self.blank = false;
+                // A line which is indented no more than the line which started a block scalar
+                // ends it
+                if matches!(self.block, Some(Block::Scalar { indent }) if self.indent <= indent) {
+                    self.block = None;
+                }
+            }
+
+            if self.block.is_none() && !self.comment {
+                self.scan(c, &text[i..]);
+            }
+            if !self.comment {
+                self.push_tail(c);
+            }
+            self.previous = c;
+        }
+    }
+
+    /// Escapes the value for where it's being written, where `following` is the template text
+    /// which comes straight after it. That's None when it's followed by another value, and empty
+    /// at the end of the template
+    pub(crate) fn escape(&self, value: &str, following: Option<&str>) -> Result<String> {
+        let escaped = match (self.format, self.quote, &self.block) {
+            (Format::Text, _, _) => value.to_string(),
+            (Format::Xml, _, _) => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&apos;"),
+            (
+                _,
+                _,
+                Some(Block::Heredoc {
+                    delimiter,
+                    expands,
+                    strip_tabs,
+                }),
+            ) => {
+                self.check_heredoc(value, following, delimiter, *strip_tabs)?;
+                // An unquoted heredoc expands variables and commands like a double quoted string
+                match expands {
+                    true => escape_shell_double(value),
+                    false => value.to_string(),
+                }
+            }
+            // A value at the start of a line which isn't indented past the key of a block scalar
+            // is after the end of it
+            (_, _, Some(Block::Scalar { indent })) if !self.blank || self.indent > *indent => {
+                self.indent_block(value)
+            }
+            _ if self.comment => single_line(value, "a comment")?,
+            (Format::Shell, Quote::Single, _) => value.replace('\'', r#"'\''"#),
+            (Format::Shell, Quote::Double, _) => escape_shell_double(value),
+            (Format::Shell, _, _) => format!("'{}'", value.replace('\'', r#"'\''"#)),
+            (_, Quote::Double, _) | (_, Quote::TripleDouble, _) => {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            (Format::Yaml, Quote::Single, _) => value.replace('\'', "''"),
+            (Format::Toml, Quote::Single, _) if value.contains(['\'', '\n']) => {
+                bail!("A value containing ' or a line break can't be written into a TOML literal string")
+            }
+            (Format::Toml, Quote::TripleSingle, _) if value.contains("'''") => {
+                bail!("A value containing ''' can't be written into a TOML literal string")
+            }
+            (_, Quote::None, _) if self.value_start() && self.value_end(following) => scalar(value),
+            // Part of a plain scalar, which a line break would end
+            (_, Quote::None, _) => single_line(value, "an unquoted value")?,
+            // Part of a multi-line literal string
+            _ => value.to_string(),
+        };
+
+        Ok(escaped)
+    }
+
+    /// Indents each line of a value written into a YAML block scalar as far as the line it's
+    /// written on, so none of them end the block. Empty lines are left empty
+    fn indent_block(&self, value: &str) -> String {
+        let indent = " ".repeat(self.indent);
+        let mut indented = String::with_capacity(value.len());
+
+        for (i, line) in lines(value).enumerate() {
+            if i > 0 {
+                indented.push('\n');
+                if !line.is_empty() {
+                    indented.push_str(&indent);
+                }
+            }
+            indented.push_str(line);
+        }
+        indented
+    }
+
+    /// Fails if a line of the value, along with the text around it, is just the delimiter of the
+    /// heredoc it's written into, as it would end the heredoc
+    fn check_heredoc(
+        &self,
+        value: &str,
+        following: Option<&str>,
+        delimiter: &str,
+        strip_tabs: bool,
+    ) -> Result<()> {
+        let last = value.matches('\n').count();
+
+        for (i, line) in value.split('\n').enumerate() {
+            // The start of a line too long to keep isn't known, but it's longer than a delimiter
+            if i == 0 && self.truncated {
+                continue;
+            }
+
+            let mut line = Cow::Borrowed(line);
+            if i == 0 {
+                line = Cow::Owned(format!("{}{}", self.tail, line));
+            }
+            if let (true, Some(following)) = (i == last, following) {
+                line.to_mut()
+                    .push_str(following.split('\n').next().unwrap_or_default());
+            }
+
+            let line = match strip_tabs {
+                true => line.trim_start_matches('\t'),
+                false => &line,
+            };
+            if line.trim_end_matches('\r') == delimiter {
+                bail!(
+                    "A value containing a line with just '{}' can't be written into a heredoc ending with it",
+                    delimiter
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Updates whether the output is inside a quoted string or comment after the character
+    fn scan(&mut self, c: char, rest: &str) {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return;
+        }
+        if self.escaping {
+            self.escaping = false;
+            return;
+        }
+
+        let toml = self.format == Format::Toml;
+        let whitespace = matches!(self.previous, ' ' | '\t' | '\n');
+        // Quotes in the middle of a plain YAML scalar, e.g. `it's`, don't start a string
+        let starts = self.format != Format::Yaml
+            || whitespace
+            || matches!(self.previous, ':' | '[' | '{' | ',' | '-');
+
+        self.quote = match (self.quote, c) {
+            // Backslashes escape the next character in double quoted strings, and in shell
+            // scripts outside of quotes
+            (Quote::Double, '\\') | (Quote::TripleDouble, '\\') => {
+                self.escaping = true;
+                self.quote
+            }
+            (Quote::None, '\\') if self.format == Format::Shell => {
+                self.escaping = true;
+                self.quote
+            }
+            (Quote::None, '#') if self.format != Format::Json && (whitespace || toml) => {
+                self.comment = true;
+                self.quote
+            }
+            (Quote::None, '<') if self.format == Format::Shell && rest.starts_with("<<") => {
+                if self.previous != '<' && !rest.starts_with("<<<") {
+                    self.heredoc = heredoc(&rest[2..]);
+                }
+                self.quote
+            }
+            (Quote::None, '"') if toml && rest.starts_with("\"\"\"") => {
+                self.skip = 2;
+                Quote::TripleDouble
+            }
+            (Quote::TripleDouble, '"') if rest.starts_with("\"\"\"") => {
+                self.skip = 2;
+                Quote::None
+            }
+            (Quote::None, '\'') if toml && rest.starts_with("'''") => {
+                self.skip = 2;
+                Quote::TripleSingle
+            }
+            (Quote::TripleSingle, '\'') if rest.starts_with("'''") => {
+                self.skip = 2;
+                Quote::None
+            }
+            (Quote::None, '"') if starts => Quote::Double,
+            (Quote::Double, '"') => Quote::None,
+            (Quote::None, '\'') if starts && self.format != Format::Json => Quote::Single,
+            (Quote::Single, '\'') => Quote::None,
+            (quote, _) => quote,
+        };
+    }
+
+    /// Keeps the end of the current line
+    fn push_tail(&mut self, c: char) {
+        self.tail.push(c);
+        if self.tail.len() > TAIL_LEN * 2 {
+            let mut cut = self.tail.len() - TAIL_LEN;
+            while !self.tail.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.tail.drain(..cut);
+            self.truncated = true;
+        }
+    }
+
+    /// Starts or ends any block at the end of a line, and starts following the next one
+    fn end_line(&mut self) {
+        // A heredoc ends at a line with just it's delimiter
+        let ends = match &self.block {
+            Some(Block::Heredoc {
+                delimiter,
+                strip_tabs,
+                ..
+            }) if !self.truncated => {
+                let line = match strip_tabs {
+                    true => self.tail.trim_start_matches('\t'),
+                    false => &self.tail,
+                };
+                line.trim_end_matches('\r') == delimiter
+            }
+            _ => false,
+        };
+        if ends {
+            self.block = None;
+        }
+
+        if self.block.is_none() {
+            if let Some(heredoc) = self.heredoc.take() {
+                self.block = Some(heredoc);
+            } else if self.format == Format::Yaml
+                && self.quote == Quote::None
+                && block_scalar(&self.tail)
+            {
+                self.block = Some(Block::Scalar {
+                    indent: self.indent,
+                });
+            }
+        }
+
+        self.escaping = false;
+        self.comment = false;
+        self.previous = '\n';
+        self.tail.clear();
+        self.truncated = false;
+        self.indent = 0;
+        self.blank = true;
+    }
+
+    /// Whether the output is at the start of an unquoted value, e.g. after `key: ` in YAML
+    fn value_start(&self) -> bool {
+        if self.blank {
+            return true;
+        }
+
+        let line = self.tail.trim_end();
+        let spaced = line.len() < self.tail.len();
+        match self.format {
+            Format::Json => line.ends_with([':', '[', ',']),
+            Format::Yaml => {
+                line.ends_with(['[', '{', ','])
+                    || (spaced && line.ends_with(':'))
+                    || (spaced && (line.trim_start() == "-" || line.ends_with(" -")))
+            }
+            Format::Toml => line.ends_with(['=', '[', ',']),
+            _ => false,
+        }
+    }
+
+    /// Whether the text following a value ends it, e.g. a line break or `,`
+    fn value_end(&self, following: Option<&str>) -> bool {
+        let following = match following {
+            Some(following) => following.split('\n').next().unwrap_or_default(),
+            None => return false,
+        };
+
+        let rest = following.trim_start();
+        let comment = self.format == Format::Toml
+            || (self.format == Format::Yaml && rest.len() < following.len());
+        rest.is_empty() || rest.starts_with([',', ']', '}']) || (comment && rest.starts_with('#'))
+    }
+}
+
+impl Default for Escaper {
+    fn default() -> Self {
+        Self::new(Format::default())
+    }
+}
+
+/// The kind of quoted string a value is being written into
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+    /// A TOML `'''` multi-line literal string
+    TripleSingle,
+    /// A TOML `"""` multi-line basic string
+    TripleDouble,
+}
+
+/// A block of lines which values are written into as they are, or escaped differently
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Block {
+    /// A YAML `|` or `>` block scalar, with the indentation of the line which started it
+    Scalar { indent: usize },
+    /// A shell heredoc, which ends at a line with just it's delimiter. Variables and commands are
+    /// expanded in it unless the delimiter is quoted
+    Heredoc {
+        delimiter: String,
+        expands: bool,
+        strip_tabs: bool,
+    },
+}
+
+/// Parses the delimiter of a heredoc from the text after it's `<<`
+fn heredoc(text: &str) -> Option<Block> {
+    let (strip_tabs, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let word = text
+        .trim_start_matches([' ', '\t'])
+        .split(|c: char| c.is_whitespace() || matches!(c, ';' | '|' | '&' | '<' | '>' | '(' | ')'))
+        .next()
+        .unwrap_or_default();
+
+    // Quoting any part of the delimiter turns off expansion
+    let delimiter = word.replace(['\'', '"', '\\'], "");
+    if delimiter.is_empty() {
+        return None;
+    }
+
+    Some(Block::Heredoc {
+        expands: delimiter.len() == word.len(),
+        delimiter,
+        strip_tabs,
+    })
+}
+
+/// Whether a YAML line ends with a block scalar indicator, e.g. `script: |` or `- >-`
+fn block_scalar(line: &str) -> bool {
+    let line = line.trim_end();
+    let (before, indicator) = match line.rfind([' ', '\t']) {
+        Some(i) => (line[..i].trim_end(), &line[i + 1..]),
+        None => ("", line),
+    };
+
+    let mut chars = indicator.chars();
+    matches!(chars.next(), Some('|') | Some('>'))
+        && chars.all(|c| matches!(c, '-' | '+' | '1'..='9'))
+        && (before.is_empty() || before.ends_with([':', '-']) || before == "---")
+}
+
+/// Splits a value into lines, where a line ends at `\n`, `\r\n` or `\r` as it does in YAML
+fn lines(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split('\n')
+        .flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+}
+
+/// Fails if the value has a line break, which would end the comment or unquoted value it's
+/// written into
+fn single_line(value: &str, context: &str) -> Result<String> {
+    if value.contains(['\n', '\r']) {
+        bail!(
+            "A value containing a line break can't be written into {}",
+            context
+        );
+    }
+    Ok(value.to_string())
+}
+
+/// Escapes a value for a double quoted shell string or an unquoted heredoc
+fn escape_shell_double(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes a value which is the whole of an unquoted JSON, YAML or TOML value, keeping numbers
+/// and booleans as they are and quoting anything else
+fn scalar(value: &str) -> String {
+    match serde_json::from_str(value) {
+        Ok(serde_json::Value::Number(_)) | Ok(serde_json::Value::Bool(_))
+            if value.trim() == value =>
+        {
+            value.to_string()
+        }
+        _ => serde_json::Value::from(value).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Format::Json, Format::from_path("config.json"));
+        assert_eq!(Format::Json, Format::from_path("/etc/app/config.json.tmpl"));
+        assert_eq!(Format::Yaml, Format::from_path("values.yml.tpl"));
+        assert_eq!(Format::Shell, Format::from_path("start.sh"));
+        assert_eq!(Format::Xml, Format::from_path("pom.xml"));
+        assert_eq!(Format::Toml, Format::from_path("Cargo.toml.tmpl"));
+        assert_eq!(Format::Text, Format::from_path("nginx.conf.tmpl"));
+        assert_eq!(Format::Text, Format::from_path("config.tmpl"));
+    }
+
+    /// Escapes the value written between the text before and after it
+    fn escape(format: Format, before: &str, value: &str, after: &str) -> String {
+        let mut escaper = Escaper::new(format);
+        escaper.push(before);
+        escaper.escape(value, Some(after)).unwrap()
+    }
+
+    /// Whether escaping the value written between the text before and after it fails
+    fn fails(format: Format, before: &str, value: &str, after: &str) -> bool {
+        let mut escaper = Escaper::new(format);
+        escaper.push(before);
+        escaper.escape(value, Some(after)).is_err()
+    }
+
+    #[test]
+    fn test_escape_json() {
+        let value = "a \"quoted\"\nvalue";
+
+        assert_eq!(
+            r#"a \"quoted\"\nvalue"#,
+            escape(Format::Json, r#"{"key": ""#, value, r#""}"#)
+        );
+        assert_eq!(
+            r#""a \"quoted\"\nvalue""#,
+            escape(Format::Json, r#"{"key": "value", "other": "#, value, "}")
+        );
+        // Apostrophes don't start strings in JSON
+        assert_eq!(
+            r#""it's""#,
+            escape(Format::Json, r#"{"it's": "#, "it's", "}")
+        );
+    }
+
+    #[test]
+    fn test_escape_yaml() {
+        assert_eq!(
+            r#"say \"hi\""#,
+            escape(Format::Yaml, r#"key: ""#, r#"say "hi""#, "\"\n")
+        );
+        assert_eq!("it''s", escape(Format::Yaml, "key: '", "it's", "'\n"));
+        assert_eq!(r#""it's""#, escape(Format::Yaml, "it's: ", "it's", "\n"));
+        assert_eq!(
+            r#""a: b\nc""#,
+            escape(Format::Yaml, "key: ", "a: b\nc", "\n")
+        );
+        assert_eq!(
+            r#""a""#,
+            escape(Format::Yaml, "list:\n  - ", "a", " # comment\n")
+        );
+        assert_eq!(r#""a""#, escape(Format::Yaml, "key: [x, ", "a", "]\n"));
+
+        // Quoted strings can span lines
+        assert_eq!(
+            r#"\"b\""#,
+            escape(Format::Yaml, "key: \"first line\n  ", "\"b\"", "\"\n")
+        );
+        // Apostrophes in comments don't start strings
+        assert_eq!(
+            r#""b""#,
+            escape(Format::Yaml, "# it's a 'comment\nkey: ", "b", "\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_yaml_mid_scalar() {
+        // Values in the middle of a plain scalar are written as they are
+        assert_eq!(
+            "v1",
+            escape(Format::Yaml, "image: repo/", "v1", ":latest\n")
+        );
+        assert_eq!("v1", escape(Format::Yaml, "image: repo:", "v1", "\n"));
+        assert_eq!("a", escape(Format::Yaml, "key: ", "a", " b\n"));
+        // Keys aren't values
+        assert_eq!("a", escape(Format::Yaml, "", "a", ": b\n"));
+
+        // A value followed by another value is part of the same scalar
+        let mut escaper = Escaper::new(Format::Yaml);
+        escaper.push("key: ");
+        assert_eq!("a", escaper.escape("a", None).unwrap());
+        assert_eq!(r#""a""#, escaper.escape("a", Some("")).unwrap());
+    }
+
+    #[test]
+    fn test_escape_yaml_block_scalar() {
+        for indicator in &["|", ">", "|-", ">+", "|2"] {
+            let before = format!("script: {}\n  echo \"", indicator);
+            assert_eq!("a'b", escape(Format::Yaml, &before, "a'b", "\"\n"));
+        }
+
+        let before = "items:\n  - |\n    first\n\n    ";
+        assert_eq!("\"a\"", escape(Format::Yaml, before, "\"a\"", "\n"));
+
+        // A line which isn't indented past the key ends the block
+        let before = "script: |\n  echo \"it's\n  done\nnext: ";
+        assert_eq!(r#""a""#, escape(Format::Yaml, before, "a", "\n"));
+
+        // A | in a plain scalar isn't a block scalar
+        let before = "command: a | b\nnext: ";
+        assert_eq!(r#""a""#, escape(Format::Yaml, before, "a", "\n"));
+    }
+
+    #[test]
+    fn test_escape_keeps_scalar_types() {
+        assert_eq!("8080", escape(Format::Yaml, "port: ", "8080", "\n"));
+        assert_eq!("1.5", escape(Format::Yaml, "ratio: ", "1.5", "\n"));
+        assert_eq!("true", escape(Format::Yaml, "debug: ", "true", "\n"));
+        assert_eq!(r#""08""#, escape(Format::Yaml, "port: ", "08", "\n"));
+        assert_eq!(r#"" 1""#, escape(Format::Yaml, "port: ", " 1", "\n"));
+        assert_eq!("8080", escape(Format::Json, r#"{"port": "#, "8080", "}"));
+        assert_eq!("false", escape(Format::Json, "[true, ", "false", "]"));
+        assert_eq!("8080", escape(Format::Toml, "port = ", "8080", "\n"));
+        assert_eq!(r#""yes""#, escape(Format::Toml, "debug = ", "yes", "\n"));
+
+        // Quoted values stay strings
+        assert_eq!(
+            "8080",
+            escape(Format::Json, r#"{"port": ""#, "8080", r#""}"#)
+        );
+    }
+
+    #[test]
+    fn test_escape_toml() {
+        assert_eq!(
+            r#"a\"b\nc"#,
+            escape(Format::Toml, "key = \"", "a\"b\nc", "\"\n")
+        );
+        assert_eq!(
+            r#"a\"b"#,
+            escape(Format::Toml, "key = \"\"\"\nfirst\n", "a\"b", "\"\"\"\n")
+        );
+
+        // Literal strings can't be escaped, so only values which fit are written into them
+        assert_eq!(
+            r"C:\path",
+            escape(Format::Toml, "key = '", r"C:\path", "'\n")
+        );
+        assert_eq!("a\nb", escape(Format::Toml, "key = '''\n", "a\nb", "'''\n"));
+        let mut escaper = Escaper::new(Format::Toml);
+        escaper.push("key = '");
+        assert!(escaper.escape("it's", Some("'\n")).is_err());
+
+        // Literal strings and comments end
+        assert_eq!(
+            r#""b""#,
+            escape(Format::Toml, "a = 'x' # it's\nb = ", "b", "\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_shell() {
+        let value = "$(rm -rf /) it's `x` \"y\"";
+
+        assert_eq!(
+            r#"'$(rm -rf /) it'\''s `x` "y"'"#,
+            escape(Format::Shell, "echo ", value, "\n")
+        );
+        assert_eq!(
+            r#"$(rm -rf /) it'\''s `x` "y""#,
+            escape(Format::Shell, "echo '", value, "'\n")
+        );
+        assert_eq!(
+            r#"\$(rm -rf /) it's \`x\` \"y\""#,
+            escape(Format::Shell, r#"echo "it's \"#, value, "\"\n")
+        );
+        // Quotes span lines, and apostrophes in comments don't start strings
+        assert_eq!(
+            r#"\$HOME"#,
+            escape(Format::Shell, "# it's\necho \"a\n", "$HOME", "\"\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_shell_heredoc() {
+        let value = "$HOME `id` \\ it's";
+
+        // Values are expanded in an unquoted heredoc, so they're escaped as if double quoted
+        assert_eq!(
+            r#"\$HOME \`id\` \\ it's"#,
+            escape(Format::Shell, "cat <<EOF > out\nline\n", value, "\nEOF\n")
+        );
+        assert_eq!(
+            r#"\$HOME \`id\` \\ it's"#,
+            escape(Format::Shell, "cat <<-EOF\n\tline\n\t", value, "\n\tEOF\n")
+        );
+
+        // Nothing is expanded in a quoted heredoc
+        for heredoc in &["<<'EOF'", "<< \"EOF\"", "<<\\EOF"] {
+            let before = format!("cat {}\n", heredoc);
+            assert_eq!(value, escape(Format::Shell, &before, value, "\nEOF\n"));
+        }
+
+        // After the delimiter, values are quoted again
+        assert_eq!(
+            "'$HOME'",
+            escape(Format::Shell, "cat <<EOF\nit's\nEOF\necho ", "$HOME", "\n")
+        );
+        assert_eq!(
+            "'$HOME'",
+            escape(Format::Shell, "cat <<<\"$x\"\necho ", "$HOME", "\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_shell_heredoc_delimiter() {
+        let value = "x\nEOF\nrm -rf /tmp/pwned";
+
+        // A value can't end the heredoc it's written into, whether it's quoted or not
+        for heredoc in &["<<EOF", "<<'EOF'"] {
+            let before = format!("cat {}\n", heredoc);
+            assert!(fails(Format::Shell, &before, value, "\nEOF\n"));
+        }
+        assert!(fails(Format::Shell, "cat <<-EOF\n", "x\n\tEOF", "\nEOF\n"));
+        // Including along with the text around it
+        assert!(fails(Format::Shell, "cat <<EOF\nE", "O", "F\nEOF\n"));
+        assert!(fails(Format::Shell, "cat <<EOF\n", "x\nE", "OF\nEOF\n"));
+
+        assert_eq!(
+            "x\nEOF2",
+            escape(Format::Shell, "cat <<EOF\n", "x\nEOF2", "\nEOF\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_line_breaks() {
+        // A line break would end a comment
+        assert!(fails(
+            Format::Shell,
+            "# deployed by ",
+            "x\nrm -rf /tmp/pwned",
+            "\necho hi\n"
+        ));
+        assert!(fails(
+            Format::Yaml,
+            "# deployed by ",
+            "x\nadmin: true",
+            "\n"
+        ));
+        assert!(fails(Format::Yaml, "key: a # ", "x\radmin: true", "\n"));
+        assert!(fails(
+            Format::Toml,
+            "# deployed by ",
+            "x\nadmin = true",
+            "\n"
+        ));
+
+        // or a plain scalar
+        assert!(fails(
+            Format::Yaml,
+            "image: repo/",
+            "x\nadmin: true",
+            ":v1\n"
+        ));
+        assert!(fails(Format::Toml, "port = 80", "80\nadmin = true", "\n"));
+
+        // but can be written into strings which span lines
+        assert_eq!(
+            r#""x\nadmin: true""#,
+            escape(Format::Yaml, "key: ", "x\nadmin: true", "\n")
+        );
+        assert_eq!(
+            "x\nadmin = true",
+            escape(Format::Toml, "key = '''\n", "x\nadmin = true", "'''\n")
+        );
+    }
+
+    #[test]
+    fn test_escape_yaml_block_scalar_lines() {
+        // Each line is indented as far as the line the value is written on
+        assert_eq!(
+            "x\n    admin: true\n\n    y",
+            escape(Format::Yaml, "key: |\n    ", "x\nadmin: true\n\ny", "\n")
+        );
+        assert_eq!(
+            "x\n  admin: true",
+            escape(Format::Yaml, "key: >\n  echo ", "x\r\nadmin: true", "\n")
+        );
+
+        // A value at the start of a line which isn't indented past the key is after the block
+        assert_eq!(
+            "name",
+            escape(Format::Yaml, "key: |\n  first\n", "name", ": b\n")
+        );
+        assert!(fails(
+            Format::Yaml,
+            "key: |\n  first\n",
+            "a\nadmin: true",
+            ": b\n"
+        ));
+    }
+
+    #[test]
+    fn test_escape_pushed_in_pieces() {
+        // The context is kept as the output is pushed a piece at a time, as when streaming
+        let mut escaper = Escaper::new(Format::Yaml);
+        for piece in &["key: \"", "first\n", "  second "] {
+            escaper.push(piece);
+        }
+        assert_eq!(r#"\"a\""#, escaper.escape("\"a\"", Some("\"")).unwrap());
+    }
+
+    #[test]
+    fn test_escape_xml() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;",
+            escape(
+                Format::Xml,
+                "<value>",
+                "<a href=\"x\">Tom & Jerry's</a>",
+                "</value>"
+            )
+        );
+    }
+}
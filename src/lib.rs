@@ -13,9 +13,12 @@
 //!
 //! | Source | Key | Feature | Description |
 //! |-|-|-|-|
-//! | [AWS EC2 Instance Tags](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/Using_Tags.html) | `awsec2tag` | `aws` | Load the value of AWS EC2 Instance Tags by their key |
-//! | [AWS EC2 Metadata Service](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/instancedata-data-retrieval.html) | `awsec2metadata` | `aws` | Load a value from the AWS EC2 Metadata Service by it's path |
-//! | [AWS SSM Parameter](https://docs.aws.amazon.com/systems-manager/latest/userguide/systems-manager-parameter-store.html) | `awsssm` | `aws` | Load a value from the AWS SSM Parameter Store by it's name. **LIMITATION** when running on an EC2 instance, the parameter must be in the same region as the instance |
+//! | [AWS EC2 Resources](https://docs.aws.amazon.com/AWSEC2/latest/APIReference/Welcome.html) | `awsec2` | `aws` | Load a field from the description of the current instance (`instance`), it's VPC (`vpc`), subnet (`subnet`) or Auto Scaling group (`asg`), or from every instance matching a set of filters, e.g. `tag:Role=db#PrivateIpAddress` |
+//! | [AWS EC2 Instance Tags](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/Using_Tags.html) | `awsec2tag` | `aws` | Load the value of AWS EC2 Instance Tags by their key. Tags are read from the instance metadata when instance metadata tags are enabled, otherwise the `ec2:DescribeInstances` permission is required |
+//! | [AWS EC2 Metadata Service](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/instancedata-data-retrieval.html) | `awsec2metadata` | `aws` | Load a value from the AWS EC2 Metadata Service by it's path. Paths starting with `dynamic/` are loaded from the dynamic data category and a field can be selected from JSON documents with a `#` suffix, e.g. `dynamic/instance-identity/document#accountId` |
+//! | [AWS ECS Task Metadata](https://docs.aws.amazon.com/AmazonECS/latest/developerguide/task-metadata-endpoint-v4.html) | `awsecs` | `aws` | Load a field from the ECS task (`task`) or container (`container`) metadata, e.g. `task#TaskARN` or `container#Name` |
+//! | [AWS S3 Object](https://docs.aws.amazon.com/AmazonS3/latest/userguide/Welcome.html) | `awss3` | `aws` | Load the contents of an S3 object by it's bucket and key, e.g. `my-bucket/certs/server.pem`. Supports `?versionId=` and `?maxSize=` (default 1 MiB) parameters and a `#` suffix to select a field from JSON objects. A `?` or `#` in the object key is escaped with a backslash |
+//! | [AWS SSM Parameter](https://docs.aws.amazon.com/systems-manager/latest/userguide/systems-manager-parameter-store.html) | `awsssm` | `aws` | Load a value from the AWS SSM Parameter Store by it's name. **LIMITATION** the parameter must be in the same region as the instance or task, unless `AWS_REGION` is set |
 //! | Environment Variables | `env` | `-` | Load the value of an environment variable |
 //!
 //! ### Example
@@ -64,9 +67,285 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! ## Sharing Loaders
+//! Each seed creates and initialises it's own loaders by default. When rendering many templates,
+//! a [`LoaderRegistry`](struct.LoaderRegistry.html) can be shared between the seeds instead, so
+//! each loader is only initialised once and custom loaders only need to be added once
+//!
+//! ```
+//! # use germinate::{LoaderRegistry, Seed, Loader};
+//! # use std::error::Error;
+//! # struct NameLoader {}
+//! # #[async_trait::async_trait]
+//! # impl Loader for NameLoader {
+//! #     async fn load(&self, key: &str) -> anyhow::Result<String> {
+//! #         Ok(String::from("John"))
+//! #     }
+//! # }
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>>{
+//! let registry = LoaderRegistry::new();
+//! registry.add_custom_loader("name".to_string(), Box::new(NameLoader {}));
+//!
+//! let mut hi = Seed::new("Hi %name:name%");
+//! hi.set_loader_registry(registry.clone());
+//!
+//! let mut bye = Seed::new("Bye %name:name%");
+//! bye.set_loader_registry(registry);
+//!
+//! assert_eq!("Hi John", hi.germinate().await?);
+//! assert_eq!("Bye John", bye.germinate().await?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Caching Values
+//! Values loaded from a source can be cached using
+//! [`Seed::set_cache`](struct.Seed.html#method.set_cache), or
+//! [`LoaderRegistry::set_cache`](struct.LoaderRegistry.html#method.set_cache) to share the cache
+//! between seeds. Values are kept for the configured TTL, and keys which weren't found can be
+//! remembered too by setting `not_found_ttl`. Custom loaders can also be wrapped in a
+//! [`CachedLoader`](struct.CachedLoader.html) directly
+//!
+//! ## Timeouts and Retries
+//! Initialising a loader and loading a value are each given 30 seconds by default, and are tried
+//! up to 3 times if they time out or fail with a [`Retryable`](struct.Retryable.html) error, such
+//! as an AWS throttling error. Use
+//! [`Seed::set_retry_policy`](struct.Seed.html#method.set_retry_policy) or
+//! [`Seed::set_source_retry_policy`](struct.Seed.html#method.set_source_retry_policy) to change
+//! this, and [`Seed::set_deadline`](struct.Seed.html#method.set_deadline) to limit how long
+//! rendering the whole template can take
+//!
+//! ## Rate Limiting
+//! When many hosts render templates at once, such as a fleet of instances booting together,
+//! [`Seed::set_rate_limit`](struct.Seed.html#method.set_rate_limit) limits how often values are
+//! loaded from a source so it isn't throttled. The limit is a token bucket, see
+//! [`RateLimit`](struct.RateLimit.html), shared by every template rendered using the same
+//! [`LoaderRegistry`](struct.LoaderRegistry.html), including templates rendered concurrently.
+//! Within a template, placeholders are loaded one after another, so the limit only spaces their
+//! requests out once the burst is used up. Values served from a cache don't take tokens
+//!
+//! ## Offline Rendering
+//! [`Seed::set_offline_cache`](struct.Seed.html#method.set_offline_cache) stores every loaded
+//! value in a file encrypted with a random 32 byte key. When a source fails to initialise or a value fails to load, for
+//! example because the network isn't ready yet, the last value loaded is used instead as long as
+//! it's no older than the configured max staleness. Each stale value is logged as a warning and
+//! listed in [`Report::stale`](struct.Report.html#method.stale)
+//!
+//! ## Secrets
+//! Loaders can mark sensitive values as secret by returning a
+//! [`Value::Secret`](enum.Value.html) from `Loader::load_value`, as the `awsssm` source does for
+//! `SecureString` parameters. Secrets are written into the rendered template and returned in full
+//! by [`Seed::parse`](struct.Seed.html#method.parse), but are shown as `********` in their `Debug`
+//! and `Display` output, in the values returned by
+//! [`Seed::parse_redacted`](struct.Seed.html#method.parse_redacted) and in any keys listed in a
+//! [`Report`](struct.Report.html). The memory holding a [`Secret`](struct.Secret.html) is zeroed
+//! when it's dropped, but copies of it, such as the rendered template or the strings returned by
+//! `Value::expose` and `Loader::load`, aren't
+//!
+//! # Templates
+//! ## Optional Values
+//! Placeholders fail to render when their value doesn't exist. Adding `?` after the source key
+//! marks a placeholder as optional, so it renders as an empty string instead. Any other failure,
+//! such as a network error, still fails the render
+//!
+//! ```rust
+//! # use germinate::Seed;
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::remove_var("EXTRA_JAVA_OPTS");
+//! let mut seed = Seed::new("java %env?:EXTRA_JAVA_OPTS% -jar app.jar");
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!("java  -jar app.jar", output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Fallback Chains
+//! A placeholder can try several sources in order, using the value from the first source that
+//! has one. Sources can share a key, e.g. `%env,awsssm:DB_HOST%`, or each use their own key by
+//! separating them with `||`, e.g. `%env:DB_HOST || awsssm:/app/db/host%`. This makes it easy to
+//! override values locally with environment variables. Only a missing value moves on to the next
+//! source, any other failure stops the chain
+//!
+//! ```rust
+//! # use germinate::Seed;
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::remove_var("DB_HOST");
+//! # std::env::set_var("DEFAULT_DB_HOST", "localhost");
+//! let mut seed = Seed::new("host=%env:DB_HOST || env:DEFAULT_DB_HOST%");
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!("host=localhost", output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Nested Placeholders
+//! Placeholders can be nested inside the key of another placeholder, e.g.
+//! `%awsssm:/app/%env:STAGE%/db/host%`. The nested placeholders are loaded first and their values
+//! used to build the key. Loaded values are never parsed for placeholders themselves, so a key can
+//! only depend on the placeholders written inside it and can't refer back to itself. Placeholders
+//! can be nested up to 8 levels deep
+//!
+//! ```rust
+//! # use germinate::Seed;
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::set_var("STAGE", "DEV");
+//! # std::env::set_var("DEV_DB_HOST", "dev.db.local");
+//! let mut seed = Seed::new("host=%env:%env:STAGE%_DB_HOST%");
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!("host=dev.db.local", output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Conditional Sections
+//! Sections of a template can be included based on a loaded value using `%#if%` blocks, with
+//! optional `%#elif%` and `%#else%` branches, closed by `%#end%`. A condition is written like a
+//! placeholder without the `%` delimiters, and can compare the value using `==` or `!=`. Without
+//! a comparison, the condition is true when the value exists and isn't empty, `false` or `0`.
+//! Prefix the condition with `!` to negate it. A value which doesn't exist is never a failure
+//! in a condition, which makes it easy to check whether something like an EC2 tag exists.
+//!
+//! Placeholders inside a section which isn't rendered are never loaded, and block tags on a line
+//! of their own are removed along with the line
+//!
+//! ```rust
+//! # use germinate::Seed;
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::set_var("TLS_ENABLED", "true");
+//! let mut seed = Seed::new(
+//!     "listen 443
+//! %#if env:TLS_ENABLED == true%
+//! ssl on
+//! %#else%
+//! ssl off
+//! %#end%
+//! ",
+//! );
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!("listen 443\nssl on\n", output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Loops
+//! A section of a template can be repeated for each element of a list using `%#for%` blocks,
+//! closed by `%#end%`. The element is available inside the block as a variable, e.g. `%$host%`,
+//! along with it's index when a second name is given, e.g. `%#for i, host in env:BACKENDS%`.
+//! Variables can be used anywhere a placeholder can, including in keys and conditions, e.g.
+//! `%#if $i == 0%`.
+//!
+//! Lists are loaded using [`Loader::load_value`](trait.Loader.html#method.load_value), which
+//! returns a typed [`Value`](enum.Value.html). Lists, such as EC2 filter results, metadata
+//! listings or SSM `StringList` parameters, are looped over element by element and strings are
+//! split on commas. When an element is structured, a field can be selected from it with a `#`
+//! suffix, e.g. `%$instance#PrivateIpAddress%`. Selecting a field which doesn't exist fails the
+//! render, but is treated as a missing value in a condition
+//!
+//! ```rust
+//! # use germinate::Seed;
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::set_var("BACKENDS", "10.0.0.1,10.0.0.2");
+//! let mut seed = Seed::new(
+//!     "upstream app {
+//! %#for host in env:BACKENDS%
+//!   server %$host%:8080;
+//! %#end%
+//! }",
+//! );
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!(
+//!     "upstream app {\n  server 10.0.0.1:8080;\n  server 10.0.0.2:8080;\n}",
+//!     output
+//! );
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Escaping
+//! When a value containing quotes or newlines is written into a JSON, YAML, XML or shell file, it
+//! can break the file, or in the case of a shell script, run commands. Setting the output
+//! [`Format`](enum.Format.html) with
+//! [`Seed::set_format`](struct.Seed.html#method.set_format) escapes values for where they're
+//! written, such as inside a quoted string or a heredoc. A value which is the whole of an unquoted
+//! value keeps it's type, so a number stays a number, and a value in the middle of a plain scalar
+//! is written as it is. The format can be detected from a file name with
+//! [`Format::from_path`](enum.Format.html#method.from_path). Add `|raw` to the end of a
+//! placeholder to write it's value as it is
+//!
+//! ```rust
+//! # use germinate::{Format, Seed};
+//! # use std::error::Error;
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn Error>> {
+//! # std::env::set_var("MOTD", "it's a \"test\"");
+//! # std::env::set_var("PORT", "8080");
+//! let mut seed = Seed::new(r#"{"motd": "%env:MOTD%", "port": %env:PORT%}"#);
+//! seed.set_format(Format::from_path("config.json.tmpl"));
+//! let output = seed.germinate().await?;
+//!
+//! assert_eq!(r#"{"motd": "it's a \"test\"", "port": 8080}"#, output);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Structured Documents
+//! JSON, YAML and TOML templates can also be rendered as documents using
+//! [`Seed::germinate_structured`](struct.Seed.html#method.germinate_structured). The template is
+//! parsed in the format set with `Seed::set_format`, placeholders are resolved inside it's string
+//! values, and the document is written back out, so values never need escaping. A string which is
+//! entirely a single placeholder takes the type of the loaded
+//! [`Value`](enum.Value.html), e.g. a `StringList` SSM parameter becomes a list
+//!
+//! ## Streaming
+//! Large templates can be rendered from an `AsyncRead` to an `AsyncWrite` using
+//! [`Seed::germinate_stream`](struct.Seed.html#method.germinate_stream), which only holds a line
+//! of the template in memory at a time, or a whole block for `%#if%` and `%#for%` blocks. Long
+//! lines are split, as a placeholder can't be longer than 16 KiB. Output stops at the first
+//! failure, so a value which failed to load is never written. The CLI streams templates from the
+//! input file unless they're rendered as structured documents
+pub(crate) mod document;
+pub(crate) mod error;
+pub(crate) mod format;
 #[deny(missing_docs)]
 pub(crate) mod loader;
+pub(crate) mod offline;
+pub(crate) mod ratelimit;
+pub(crate) mod registry;
+pub(crate) mod retry;
+pub(crate) mod secret;
 pub(crate) mod seed;
+pub(crate) mod template;
+pub(crate) mod value;
 
+pub use error::{Failure, NotFound, Report, Retryable, StaleValue};
+pub use format::Format;
+#[cfg(feature = "aws")]
+pub use loader::aws::AwsConfig;
+#[cfg(feature = "aws")]
+pub use loader::awsec2tag::Ec2TagMode;
+pub use loader::cache::{CacheConfig, CachedLoader};
 pub use loader::Loader;
-pub use seed::Seed;
+pub use offline::OfflineCacheConfig;
+pub use ratelimit::RateLimit;
+pub use registry::LoaderRegistry;
+pub use retry::RetryPolicy;
+pub use secret::Secret;
+pub use seed::{ErrorMode, Seed};
+pub use value::Value;
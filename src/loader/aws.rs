@@ -0,0 +1,402 @@
+//! Configuration and helpers shared by the AWS loaders
+use crate::Retryable;
+use anyhow::{anyhow, Context, Result};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+    ProfileProvider, ProvideAwsCredentials, Variable,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient, WebIdentityProvider};
+use std::sync::Arc;
+
+/// The environment variable used to override the endpoint of the AWS APIs
+pub(crate) const ENDPOINT_URL_VAR: &str = "AWS_ENDPOINT_URL";
+
+/// The environment variable used to override the endpoint of the EC2 metadata service
+pub(crate) const METADATA_ENDPOINT_VAR: &str = "AWS_EC2_METADATA_SERVICE_ENDPOINT";
+
+/// The environment variable EKS sets to the path of the service account's web identity token
+pub(crate) const WEB_IDENTITY_TOKEN_FILE_VAR: &str = "AWS_WEB_IDENTITY_TOKEN_FILE";
+
+/// The environment variable EKS sets to the role to assume with the web identity token
+pub(crate) const WEB_IDENTITY_ROLE_ARN_VAR: &str = "AWS_ROLE_ARN";
+
+const DEFAULT_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+
+/// Error codes AWS APIs return when requests are being throttled
+const THROTTLING_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+    "SlowDown",
+];
+const DEFAULT_SESSION_NAME: &str = "germinate";
+
+/// Configuration shared by all of the AWS sources
+///
+/// # Example
+/// ```
+/// use germinate::{AwsConfig, Seed};
+///
+/// let mut seed = Seed::new("%awsssm:/app/db/host%");
+///
+/// // Send all AWS API requests to a local stand-in such as LocalStack
+/// seed.set_aws_config(AwsConfig {
+///     endpoint_url: Some(String::from("http://localhost:4566")),
+///     ..Default::default()
+/// });
+///
+/// // Load SSM parameters from another account
+/// seed.set_source_aws_config("awsssm", AwsConfig {
+///     role_arn: Some(String::from("arn:aws:iam::123456789012:role/config-reader")),
+///     external_id: Some(String::from("my-external-id")),
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AwsConfig {
+    /// Overrides the endpoint used for all AWS API requests, e.g. `http://localhost:4566`
+    pub endpoint_url: Option<String>,
+
+    /// The region to create clients in, e.g. `eu-west-2`. When unset, the region is detected from
+    /// the `AWS_REGION` and `AWS_DEFAULT_REGION` environment variables, the ECS task metadata or
+    /// the EC2 metadata service
+    pub region: Option<String>,
+
+    /// Overrides the endpoint of the EC2 metadata service, e.g. `http://localhost:1338`. Requests
+    /// are made to the `latest/meta-data` and `latest/dynamic` paths of this endpoint
+    pub metadata_endpoint_url: Option<String>,
+
+    /// The profile to load credentials from in the shared credentials file. This takes priority
+    /// over a web identity token. When neither is set, the default credentials chain is used
+    pub profile: Option<String>,
+
+    /// A role to assume using the resolved credentials
+    pub role_arn: Option<String>,
+
+    /// The external ID to pass when assuming `role_arn`
+    pub external_id: Option<String>,
+
+    /// The session name to use when assuming a role, either with `role_arn` or a web identity
+    /// token. Defaults to `germinate`
+    pub session_name: Option<String>,
+
+    /// The path to a web identity token, such as the token EKS provides to pods using IAM roles
+    /// for service accounts. When set, credentials are obtained by assuming
+    /// `web_identity_role_arn` with the token instead of using the default chain. A `profile`
+    /// takes priority over the token, as the token is usually set by the environment
+    pub web_identity_token_file: Option<String>,
+
+    /// The role to assume with the web identity token
+    pub web_identity_role_arn: Option<String>,
+}
+
+/// Where the credentials used by the AWS clients come from, before any role is assumed
+#[derive(Debug, PartialEq)]
+enum BaseCredentials<'c> {
+    /// A profile in the shared credentials file
+    Profile(&'c str),
+    /// A web identity token, exchanged for credentials for the role
+    WebIdentity {
+        token_file: &'c str,
+        role_arn: &'c str,
+    },
+    /// The default credentials chain
+    Default,
+}
+
+/// A cloneable credentials provider which can be shared by the clients of a loader
+#[derive(Clone)]
+pub(crate) struct Credentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+impl Credentials {
+    fn new<P: ProvideAwsCredentials + Send + Sync + 'static>(provider: P) -> Self {
+        Self(Arc::new(provider))
+    }
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for Credentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
+
+impl AwsConfig {
+    /// Creates a new `AwsConfig` using the `AWS_ENDPOINT_URL`,
+    /// `AWS_EC2_METADATA_SERVICE_ENDPOINT`, `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`
+    /// environment variables, if they're set
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+
+        Self {
+            endpoint_url: var(ENDPOINT_URL_VAR),
+            metadata_endpoint_url: var(METADATA_ENDPOINT_VAR),
+            web_identity_token_file: var(WEB_IDENTITY_TOKEN_FILE_VAR),
+            web_identity_role_arn: var(WEB_IDENTITY_ROLE_ARN_VAR),
+            ..Default::default()
+        }
+    }
+
+    fn metadata_endpoint(&self) -> &str {
+        self.metadata_endpoint_url
+            .as_deref()
+            .unwrap_or(DEFAULT_METADATA_ENDPOINT)
+            .trim_end_matches('/')
+    }
+
+    /// The base URL for `meta-data` paths of the EC2 metadata service
+    pub(crate) fn metadata_url(&self) -> String {
+        format!("{}/latest/meta-data", self.metadata_endpoint())
+    }
+
+    /// Returns the region clients should be created with. When an endpoint override is set, the
+    /// detected region is only used for signing requests
+    pub(crate) async fn region(&self) -> Result<Region> {
+        let name = match &self.region {
+            Some(region) => region.clone(),
+            None => get_current_region(&self.metadata_url()).await?,
+        };
+        Ok(self.named_region(name))
+    }
+
+    /// Returns the region with the given name, using the endpoint override if one is set
+    fn named_region(&self, name: String) -> Region {
+        match &self.endpoint_url {
+            Some(endpoint) => Region::Custom {
+                name,
+                endpoint: endpoint.clone(),
+            },
+            // If the region can't be parsed, it will fallback to us-east-1
+            None => name.parse().unwrap_or_default(),
+        }
+    }
+
+    /// Returns where the base credentials come from. An explicit profile takes priority over a
+    /// web identity token, as the token is usually picked up from the environment, e.g. in EKS
+    fn base_credentials(&self) -> Result<BaseCredentials<'_>> {
+        match (&self.profile, &self.web_identity_token_file) {
+            (Some(profile), _) => Ok(BaseCredentials::Profile(profile)),
+            (None, Some(token_file)) => {
+                let role_arn = self.web_identity_role_arn.as_deref().ok_or_else(|| {
+                    anyhow!("A role ARN is required when using a web identity token")
+                })?;
+
+                Ok(BaseCredentials::WebIdentity {
+                    token_file,
+                    role_arn,
+                })
+            }
+            (None, None) => Ok(BaseCredentials::Default),
+        }
+    }
+
+    /// The session name used when assuming a role
+    fn session_name(&self) -> String {
+        self.session_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_string())
+    }
+
+    /// Builds the credentials provider described by the configuration. Any role is assumed using
+    /// STS in the given region
+    pub(crate) fn credentials(&self, region: &Region) -> Result<Credentials> {
+        let base = match self.base_credentials()? {
+            BaseCredentials::Profile(profile) => {
+                let mut provider = ProfileProvider::new()?;
+                provider.set_profile(profile);
+
+                Credentials::new(provider)
+            }
+            BaseCredentials::WebIdentity {
+                token_file,
+                role_arn,
+            } => {
+                let provider = WebIdentityProvider::new(
+                    Variable::from_text_file(token_file),
+                    role_arn.to_string(),
+                    Some(Some(self.session_name())),
+                );
+
+                Credentials::new(AutoRefreshingProvider::new(provider)?)
+            }
+            BaseCredentials::Default => Credentials::new(DefaultCredentialsProvider::new()?),
+        };
+
+        let role_arn = match &self.role_arn {
+            Some(role_arn) => role_arn.clone(),
+            None => return Ok(base),
+        };
+
+        let sts = StsClient::new_with(self.http_client()?, base, region.clone());
+        let provider = StsAssumeRoleSessionCredentialsProvider::new(
+            sts,
+            role_arn,
+            self.session_name(),
+            self.external_id.clone(),
+            None,
+            None,
+            None,
+        );
+
+        Ok(Credentials::new(AutoRefreshingProvider::new(provider)?))
+    }
+
+    fn http_client(&self) -> Result<HttpClient> {
+        HttpClient::new().context("Failed to create HTTP client")
+    }
+
+    /// Creates a client for an AWS service using the configured region, endpoint and credentials.
+    /// The constructor is usually the `new_with` function of the client type
+    pub(crate) async fn client<C>(
+        &self,
+        new_with: impl FnOnce(HttpClient, Credentials, Region) -> C,
+    ) -> Result<C> {
+        let region = self.region().await?;
+        let credentials = self.credentials(&region)?;
+
+        Ok(new_with(self.http_client()?, credentials, region))
+    }
+
+    /// Creates a client for an AWS service in the given region rather than the detected one, such
+    /// as for an S3 bucket in another region
+    pub(crate) fn client_in_region<C>(
+        &self,
+        name: &str,
+        new_with: impl FnOnce(HttpClient, Credentials, Region) -> C,
+    ) -> Result<C> {
+        let region = self.named_region(name.to_string());
+        let credentials = self.credentials(&region)?;
+
+        Ok(new_with(self.http_client()?, credentials, region))
+    }
+}
+
+/// Detects the region the process is running in. The `AWS_REGION` and `AWS_DEFAULT_REGION`
+/// environment variables take priority, which covers EKS pods using IAM roles for service
+/// accounts. Otherwise the region is loaded from the ECS task metadata endpoint when running in an
+/// ECS task, falling back to the EC2 metadata service
+pub(crate) async fn get_current_region(metadata_url: &str) -> Result<String> {
+    for var in &["AWS_REGION", "AWS_DEFAULT_REGION"] {
+        if let Ok(region) = std::env::var(var) {
+            if !region.is_empty() {
+                return Ok(region);
+            }
+        }
+    }
+
+    match std::env::var(crate::loader::awsecs::METADATA_URL_VAR) {
+        Ok(url) => crate::loader::awsecs::get_current_region_from_url(&url).await,
+        Err(_) => crate::loader::awsec2metadata::get_current_region_from_url(metadata_url).await,
+    }
+}
+
+/// Converts the error from an AWS API request, marking it as `Retryable` when the request couldn't
+/// be sent, or AWS responded with a throttling or server error
+pub(crate) fn api_error<E: std::error::Error + 'static>(
+    context: &str,
+    error: RusotoError<E>,
+) -> anyhow::Error {
+    let retryable = match &error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            let body = response.body_as_str();
+            is_retryable_status(response.status.as_u16())
+                || THROTTLING_CODES.iter().any(|code| {
+                    body.contains(&format!("{}<", code)) || body.contains(&format!("{}\"", code))
+                })
+        }
+        _ => false,
+    };
+
+    match retryable {
+        true => anyhow::Error::new(Retryable::new(error.to_string())).context(context.to_string()),
+        false => anyhow!("{}: {}", context, error),
+    }
+}
+
+/// Returns true if a response with the status code is worth retrying
+pub(crate) fn is_retryable_status(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_region_with_endpoint_override() {
+        let config = AwsConfig {
+            endpoint_url: Some(String::from("http://localhost:4566")),
+            region: Some(String::from("eu-west-2")),
+            metadata_endpoint_url: Some(String::from("http://localhost:1338/")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Region::Custom {
+                name: String::from("eu-west-2"),
+                endpoint: String::from("http://localhost:4566"),
+            },
+            config.region().await.unwrap()
+        );
+        assert_eq!(
+            "http://localhost:1338/latest/meta-data",
+            config.metadata_url()
+        );
+    }
+
+    #[test]
+    fn test_credentials_assume_role() {
+        let config = AwsConfig {
+            role_arn: Some(String::from("arn:aws:iam::123456789012:role/test")),
+            external_id: Some(String::from("external")),
+            ..Default::default()
+        };
+
+        assert!(config.credentials(&Region::EuWest1).is_ok());
+        assert_eq!(BaseCredentials::Default, config.base_credentials().unwrap());
+        assert_eq!("germinate", config.session_name());
+    }
+
+    #[test]
+    fn test_credentials_web_identity_requires_role() {
+        let config = AwsConfig {
+            web_identity_token_file: Some(String::from("/var/run/secrets/token")),
+            ..Default::default()
+        };
+
+        assert!(config.credentials(&Region::EuWest1).is_err());
+    }
+
+    #[test]
+    fn test_credentials_source_priority() {
+        let web_identity = AwsConfig {
+            web_identity_token_file: Some(String::from("/var/run/secrets/token")),
+            web_identity_role_arn: Some(String::from("arn:aws:iam::123456789012:role/pod")),
+            ..Default::default()
+        };
+        assert_eq!(
+            BaseCredentials::WebIdentity {
+                token_file: "/var/run/secrets/token",
+                role_arn: "arn:aws:iam::123456789012:role/pod",
+            },
+            web_identity.base_credentials().unwrap()
+        );
+        assert_eq!("germinate", web_identity.session_name());
+
+        // An explicit profile wins over a token picked up from the environment
+        let profile = AwsConfig {
+            profile: Some(String::from("deploy")),
+            session_name: Some(String::from("deploy-session")),
+            ..web_identity
+        };
+        assert_eq!(
+            BaseCredentials::Profile("deploy"),
+            profile.base_credentials().unwrap()
+        );
+        assert_eq!("deploy-session", profile.session_name());
+    }
+}
@@ -0,0 +1,456 @@
+//! Provides the ability to asynchronously load values describing [AWS EC2](https://docs.aws.amazon.com/AWSEC2/latest/APIReference/Welcome.html)
+//! resources related to the current instance, or instances matching a set of filters
+//!
+//! Keys take the form `<query>#<path>` where the path selects a field from the description of the
+//! resource, using the field names from the AWS API. Tags are exposed as a map from the tag's key
+//! to its value, so `Tags.Name` selects the value of the `Name` tag. The supported queries are:
+//!
+//! * `instance` - The current instance
+//! * `vpc` - The VPC the current instance is running in
+//! * `subnet` - The subnet the current instance is running in
+//! * `asg` - The Auto Scaling group the current instance belongs to
+//! * Anything else is treated as a list of [filters](https://docs.aws.amazon.com/AWSEC2/latest/APIReference/API_DescribeInstances.html)
+//!   in the form `name=value[,value...][&name=value...]`. The value is loaded from every matching
+//!   instance and the results are joined into a list
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut seed = germinate::Seed::new("db: %awsec2:tag:Role=db&instance-state-name=running#PrivateIpAddress%");
+//! let output = seed.germinate().await.unwrap();
+//! assert_eq!(String::from("db: 10.0.0.10,10.0.0.11"), output);
+//! ```
+use crate::loader::aws::{api_error, AwsConfig};
+use crate::loader::json;
+use crate::loader::uninitialised;
+use crate::NotFound;
+use anyhow::{anyhow, Result};
+use rusoto_autoscaling::{AutoScalingGroupNamesType, Autoscaling, AutoscalingClient};
+use rusoto_ec2::{
+    DescribeInstancesRequest, DescribeSubnetsRequest, DescribeVpcsRequest, Ec2, Ec2Client, Filter,
+};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+pub(crate) const TEMPLATE_KEY: &str = "awsec2";
+
+/// The tag AWS adds to instances launched by an Auto Scaling group
+const ASG_NAME_TAG: &str = "aws:autoscaling:groupName";
+
+/// This type provides functionality for loading values describing [AWS EC2](https://docs.aws.amazon.com/AWSEC2/latest/APIReference/Welcome.html)
+/// resources
+pub struct AwsEc2Loader {
+    config: AwsConfig,
+    clients: Option<(Ec2Client, AutoscalingClient)>,
+    metadata_url: String,
+    instance: OnceCell<Value>,
+}
+
+impl AwsEc2Loader {
+    /// Creates a new AwsEc2Loader from the given configuration. The clients are created when the
+    /// loader is initialised
+    pub fn new(config: &AwsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            clients: None,
+            metadata_url: config.metadata_url(),
+            instance: OnceCell::new(),
+        }
+    }
+
+    /// Creates a new AwsEc2Loader with the provided clients and metadata URL
+    #[cfg(test)]
+    pub fn with_clients_and_metadata_url(
+        ec2: Ec2Client,
+        autoscaling: AutoscalingClient,
+        metadata_url: &str,
+    ) -> Self {
+        Self {
+            config: AwsConfig::default(),
+            clients: Some((ec2, autoscaling)),
+            metadata_url: metadata_url.to_string(),
+            instance: OnceCell::new(),
+        }
+    }
+
+    fn ec2(&self) -> Result<&Ec2Client> {
+        self.clients
+            .as_ref()
+            .map(|(ec2, _)| ec2)
+            .ok_or_else(uninitialised)
+    }
+
+    fn autoscaling(&self) -> Result<&AutoscalingClient> {
+        self.clients
+            .as_ref()
+            .map(|(_, autoscaling)| autoscaling)
+            .ok_or_else(uninitialised)
+    }
+
+    /// Describes the current instance. The description is cached after the first call
+    async fn current_instance(&self) -> Result<&Value> {
+        self.instance
+            .get_or_try_init(|| async {
+                let instance_id = crate::loader::awsec2metadata::get_metadata_value(
+                    &self.metadata_url,
+                    "instance-id",
+                )
+                .await?;
+
+                let req = DescribeInstancesRequest {
+                    instance_ids: Some(vec![instance_id.clone()]),
+                    ..Default::default()
+                };
+
+                self.describe_instances(req)
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| {
+                        NotFound::new(format!("Instance '{}' not found", instance_id)).into()
+                    })
+            })
+            .await
+    }
+
+    /// Describes all instances matching the request, following the pagination tokens until every
+    /// page has been loaded
+    async fn describe_instances(&self, mut req: DescribeInstancesRequest) -> Result<Vec<Value>> {
+        let mut instances = Vec::new();
+
+        loop {
+            let response = self
+                .ec2()?
+                .describe_instances(req.clone())
+                .await
+                .map_err(|e| api_error("Failed to describe instances", e))?;
+
+            for reservation in response.reservations.unwrap_or_default() {
+                for instance in reservation.instances.unwrap_or_default() {
+                    instances.push(normalize(serde_json::to_value(instance)?));
+                }
+            }
+
+            match response.next_token {
+                Some(token) if !token.is_empty() => req.next_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(instances)
+    }
+
+    /// Describes the VPC the current instance is running in
+    async fn describe_vpc(&self) -> Result<Value> {
+        let vpc_id = string_field(self.current_instance().await?, "VpcId")?;
+
+        let req = DescribeVpcsRequest {
+            vpc_ids: Some(vec![vpc_id.clone()]),
+            ..Default::default()
+        };
+
+        let vpc = self
+            .ec2()?
+            .describe_vpcs(req)
+            .await
+            .map_err(|e| api_error("Failed to describe VPC", e))?
+            .vpcs
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound::new(format!("VPC '{}' not found", vpc_id)))?;
+
+        Ok(normalize(serde_json::to_value(vpc)?))
+    }
+
+    /// Describes the subnet the current instance is running in
+    async fn describe_subnet(&self) -> Result<Value> {
+        let subnet_id = string_field(self.current_instance().await?, "SubnetId")?;
+
+        let req = DescribeSubnetsRequest {
+            subnet_ids: Some(vec![subnet_id.clone()]),
+            ..Default::default()
+        };
+
+        let subnet = self
+            .ec2()?
+            .describe_subnets(req)
+            .await
+            .map_err(|e| api_error("Failed to describe subnet", e))?
+            .subnets
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound::new(format!("Subnet '{}' not found", subnet_id)))?;
+
+        Ok(normalize(serde_json::to_value(subnet)?))
+    }
+
+    /// Describes the Auto Scaling group the current instance belongs to
+    async fn describe_asg(&self) -> Result<Value> {
+        let instance = self.current_instance().await?;
+        let name = instance
+            .get("Tags")
+            .and_then(|tags| tags.get(ASG_NAME_TAG))
+            .and_then(Value::as_str)
+            .ok_or_else(|| NotFound::new("Instance isn't part of an Auto Scaling group"))?;
+
+        let req = AutoScalingGroupNamesType {
+            auto_scaling_group_names: Some(vec![name.to_string()]),
+            ..Default::default()
+        };
+
+        let group = self
+            .autoscaling()?
+            .describe_auto_scaling_groups(req)
+            .await
+            .map_err(|e| api_error("Failed to describe Auto Scaling group", e))?
+            .auto_scaling_groups
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound::new(format!("Auto Scaling group '{}' not found", name)))?;
+
+        Ok(normalize(serde_json::to_value(group)?))
+    }
+}
+
+/// Parses a filter query in the form `name=value[,value...][&name=value...]`
+fn parse_filters(query: &str) -> Result<Vec<Filter>> {
+    query
+        .split('&')
+        .map(|filter| {
+            let (name, values) = filter
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid filter '{}', expected name=value", filter))?;
+
+            Ok(Filter {
+                name: Some(name.to_string()),
+                values: Some(values.split(',').map(String::from).collect()),
+            })
+        })
+        .collect()
+}
+
+/// Returns the string field with the given name from a resource description
+fn string_field(value: &Value, name: &str) -> Result<String> {
+    value
+        .get(name)
+        .and_then(Value::as_str)
+        .map(String::from)
+        .ok_or_else(|| anyhow!("Field '{}' missing from description", name))
+}
+
+/// Converts a serialized rusoto type into the shape used by the AWS API documentation. Field names
+/// are converted to PascalCase, empty fields are removed, and lists of tags are converted into a
+/// map of the tag keys to their values
+fn normalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| {
+                    let k = pascal_case(&k);
+                    let v = normalize(v);
+
+                    if k == "Tags" {
+                        (k, tags_to_map(v))
+                    } else {
+                        (k, v)
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(normalize).collect()),
+        other => other,
+    }
+}
+
+fn tags_to_map(tags: Value) -> Value {
+    match tags {
+        Value::Array(items) => Value::Object(
+            items
+                .into_iter()
+                .filter_map(|tag| {
+                    let key = tag.get("Key")?.as_str()?.to_string();
+                    let value = tag.get("Value").cloned().unwrap_or_default();
+                    Some((key, value))
+                })
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl crate::Loader for AwsEc2Loader {
+    /// Creates the EC2 and Auto Scaling clients if they weren't provided
+    async fn init(&mut self) -> Result<()> {
+        if self.clients.is_none() {
+            self.clients = Some((
+                self.config.client(Ec2Client::new_with).await?,
+                self.config.client(AutoscalingClient::new_with).await?,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Loads a field from the description of the queried resource and returns it as a `String`.
+    /// Filter queries return the field from every matching instance as a comma separated list
+    async fn load(&self, key: &str) -> Result<String> {
+        Ok(self.load_value(key).await?.to_string())
+    }
+
+    /// Loads a field from the description of the queried resource, keeping it's structure. Filter
+    /// queries return the field from every matching instance as a list, e.g. the private IP
+    /// address of every instance matching a filter
+    async fn load_value(&self, key: &str) -> Result<crate::Value> {
+        let (query, path) = json::split_key(key);
+        let path = path.unwrap_or_default();
+
+        let value = match query {
+            "instance" => select(self.current_instance().await?.clone(), path)?,
+            "vpc" => select(self.describe_vpc().await?, path)?,
+            "subnet" => select(self.describe_subnet().await?, path)?,
+            "asg" => select(self.describe_asg().await?, path)?,
+            filters => {
+                let req = DescribeInstancesRequest {
+                    filters: Some(parse_filters(filters)?),
+                    ..Default::default()
+                };
+
+                // Instances without the requested field are skipped rather than failing the load
+                crate::Value::List(
+                    self.describe_instances(req)
+                        .await?
+                        .into_iter()
+                        .filter_map(|instance| select(instance, path).ok())
+                        .collect(),
+                )
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+/// Selects the field at the path from the description of a resource
+fn select(description: Value, path: &str) -> Result<crate::Value> {
+    Ok(crate::Value::from(description).select(path)?.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Loader;
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MockResponseReader,
+        MultipleMockRequestDispatcher, ReadMockResponse,
+    };
+
+    fn autoscaling_client() -> AutoscalingClient {
+        AutoscalingClient::new_with(
+            MockRequestDispatcher::with_status(500),
+            MockCredentialsProvider,
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        let filters = parse_filters("tag:Role=db&instance-state-name=running,stopped").unwrap();
+
+        assert_eq!(Some("tag:Role".to_string()), filters[0].name);
+        assert_eq!(Some(vec!["db".to_string()]), filters[0].values);
+        assert_eq!(
+            Some(vec!["running".to_string(), "stopped".to_string()]),
+            filters[1].values
+        );
+        assert!(parse_filters("tag:Role").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aws_ec2_load_current_instance() {
+        let ec2 = Ec2Client::new_with(
+            MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                "testdata/awsec2tag",
+                "describe-instances-response.xml",
+            )),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let m = mockito::mock("GET", "/instance-id")
+            .with_status(200)
+            .with_body("i-fde9cece")
+            .expect(1)
+            .create();
+
+        let url = &mockito::server_url();
+        let loader = AwsEc2Loader::with_clients_and_metadata_url(ec2, autoscaling_client(), url);
+
+        assert_eq!(
+            "us-west-2a",
+            loader
+                .load("instance#Placement.AvailabilityZone")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "my-instance",
+            loader.load("instance#Tags.Name").await.unwrap()
+        );
+
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn test_aws_ec2_load_filter_paginates() {
+        let ec2 = Ec2Client::new_with(
+            MultipleMockRequestDispatcher::new(vec![
+                MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                    "testdata/awsec2",
+                    "describe-instances-page-1.xml",
+                )),
+                MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                    "testdata/awsec2",
+                    "describe-instances-page-2.xml",
+                )),
+            ]),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let loader =
+            AwsEc2Loader::with_clients_and_metadata_url(ec2, autoscaling_client(), "unused");
+        let actual = loader.load("tag:Role=db#PrivateIpAddress").await.unwrap();
+
+        assert_eq!("10.0.0.10,10.0.0.11", actual);
+    }
+
+    #[test]
+    fn test_normalize_tags() {
+        let value = serde_json::json!({
+            "vpc_id": "vpc-1234",
+            "cidr_block": null,
+            "tags": [{"key": "Name", "value": "main"}],
+        });
+
+        assert_eq!(
+            serde_json::json!({"VpcId": "vpc-1234", "Tags": {"Name": "main"}}),
+            normalize(value)
+        );
+    }
+}
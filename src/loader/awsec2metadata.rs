@@ -7,27 +7,70 @@
 //! let output = seed.germinate().await.unwrap();
 //! assert_eq!(String::from("Instance ID: i-abcdefgh123456789"), output);
 //! ```
-
-// TODO handle different responses (text/json). The metadata service doesn't set the content-type
-// header correctly so this would most likely have to be handled on a case by case basis
-use anyhow::Result;
+//!
+//! Keys are relative to `meta-data` unless they start with `meta-data/` or `dynamic/`. The
+//! metadata service doesn't set the content-type header correctly, so JSON documents are only
+//! parsed when a field is selected with a `#` suffix. Paths ending in `/` are directory listings
+//! and are parsed into a list of entries.
+//!
+//! ```ignore
+//! let mut seed = germinate::Seed::new("Account: %awsec2metadata:dynamic/instance-identity/document#accountId%");
+//! let output = seed.germinate().await.unwrap();
+//! assert_eq!(String::from("Account: 123456789012"), output);
+//! ```
+use crate::loader::aws::{is_retryable_status, AwsConfig};
+use crate::loader::json;
+use crate::{NotFound, Retryable, Value};
+use anyhow::{anyhow, Context, Result};
 
 pub(crate) const TEMPLATE_KEY: &str = "awsec2metadata";
-pub(crate) const METADATA_BASE_URL: &str = "http://169.254.169.254/latest/meta-data";
 
 /// This type provides functionality for loading values from [AWS EC2 Metadata](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/instancedata-data-retrieval.html)
-pub struct AwsEc2MetadataLoader<'a> {
-    metadata_url: &'a str,
+pub struct AwsEc2MetadataLoader {
+    metadata_url: String,
+    dynamic_url: String,
 }
 
-impl<'a> AwsEc2MetadataLoader<'a> {
-    /// Creates a new AwsEc2MetadataLoader with the default AWS EC2 Metadata service endpoint
-    pub fn new() -> Self {
-        Self::with_base_url(METADATA_BASE_URL)
+impl AwsEc2MetadataLoader {
+    /// Creates a new AwsEc2MetadataLoader using the metadata service endpoint from the given
+    /// configuration
+    pub fn new(config: &AwsConfig) -> Self {
+        Self::with_base_url(&config.metadata_url())
     }
 
-    pub fn with_base_url(url: &'a str) -> Self {
-        Self { metadata_url: url }
+    /// Creates a new AwsEc2MetadataLoader with the given base URL for `meta-data` paths. When the
+    /// URL ends in `meta-data`, `dynamic` paths are loaded from the `dynamic` URL next to it,
+    /// otherwise they're loaded from the same URL
+    pub fn with_base_url(url: &str) -> Self {
+        let base = url.trim_end_matches('/');
+        let dynamic_url = match base.strip_suffix("meta-data") {
+            Some(parent) => format!("{}dynamic", parent),
+            None => url.to_string(),
+        };
+
+        Self::with_urls(url, &dynamic_url)
+    }
+
+    /// Creates a new AwsEc2MetadataLoader with separate base URLs for `meta-data` and `dynamic`
+    /// paths
+    pub fn with_urls(metadata_url: &str, dynamic_url: &str) -> Self {
+        Self {
+            metadata_url: metadata_url.to_string(),
+            dynamic_url: dynamic_url.to_string(),
+        }
+    }
+
+    /// Returns the base URL and the path relative to it for the given metadata path
+    fn resolve<'k>(&self, path: &'k str) -> (&str, &'k str) {
+        let path = path.trim_start_matches('/');
+
+        match path.strip_prefix("dynamic/") {
+            Some(path) => (&self.dynamic_url, path),
+            None => (
+                &self.metadata_url,
+                path.strip_prefix("meta-data/").unwrap_or(path),
+            ),
+        }
     }
 }
 
@@ -42,9 +85,28 @@ pub(crate) async fn get_metadata_value(base_url: &str, path: &str) -> Result<Str
     url.push_str(path.trim_start_matches('/'));
 
     // This seems overly complex, there's probably a better way
-    let value = surf::get(url)
-        .await
-        .map_err(|e| anyhow::anyhow!("{}", e).context("Failed to load metadata value"))?
+    // A request which couldn't be sent is retryable, as the metadata service may not be ready yet
+    let mut response = surf::get(url).await.map_err(|e| {
+        anyhow::Error::new(Retryable::new(e.to_string())).context("Failed to load metadata value")
+    })?;
+
+    if response.status() == surf::StatusCode::NotFound {
+        return Err(NotFound::new(format!("Metadata path '{}' not found", path)).into());
+    }
+
+    if !response.status().is_success() {
+        let message = format!(
+            "Metadata service responded with status {} for '{}'",
+            response.status(),
+            path
+        );
+        return Err(match is_retryable_status(response.status().into()) {
+            true => Retryable::new(message).into(),
+            false => anyhow!(message),
+        });
+    }
+
+    let value = response
         .body_string()
         .await
         .map_err(|e| anyhow::anyhow!("{}", e).context("Failed to decode response body"))?;
@@ -57,15 +119,42 @@ pub(crate) async fn get_current_region_from_url(base_url: &str) -> Result<String
     Ok(r.trim_end_matches(char::is_alphabetic).to_string())
 }
 
-pub(crate) async fn get_current_region() -> Result<String> {
-    get_current_region_from_url(METADATA_BASE_URL).await
-}
-
 #[async_trait::async_trait]
-impl crate::Loader for AwsEc2MetadataLoader<'_> {
-    /// Loads a value from the AWS EC2 Metadata service and returns it as a `String`
+impl crate::Loader for AwsEc2MetadataLoader {
+    /// Loads a value from the AWS EC2 Metadata service and returns it as a `String`. If the key
+    /// contains a `#`, the response is parsed as JSON and the field at the path following it is
+    /// returned
     async fn load(&self, key: &str) -> Result<String> {
-        get_metadata_value(self.metadata_url, key).await
+        Ok(self.load_value(key).await?.to_string())
+    }
+
+    /// Loads a value from the AWS EC2 Metadata service, keeping it's structure. Directory
+    /// listings are returned as a list of their entries and fields selected from JSON documents
+    /// keep their type
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        let (path, selector) = json::split_key(key);
+        let (base_url, path) = self.resolve(path);
+        let body = get_metadata_value(base_url, path).await?;
+
+        // Directory listings are returned as newline separated entries
+        let document = if path.ends_with('/') {
+            serde_json::Value::Array(
+                body.lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(|l| serde_json::Value::String(l.to_string()))
+                    .collect(),
+            )
+        } else if selector.is_some() {
+            serde_json::from_str(&body)
+                .with_context(|| anyhow!("Metadata value at '{}' is not a JSON document", path))?
+        } else {
+            return Ok(Value::String(body));
+        };
+
+        Ok(Value::from(document)
+            .select(selector.unwrap_or_default())?
+            .clone())
     }
 }
 
@@ -95,6 +184,58 @@ mod test {
         assert_eq!(expected, actual);
     }
 
+    #[tokio::test]
+    async fn test_aws_ec2_metadata_json_document() {
+        let m = mock("GET", "/instance-identity/document")
+            .with_status(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body(r#"{"accountId": "123456789012", "region": "eu-west-1"}"#)
+            .create();
+
+        let url = mockito::server_url();
+        let loader = AwsEc2MetadataLoader::with_urls(&url, &url);
+
+        let actual = loader
+            .load("dynamic/instance-identity/document#accountId")
+            .await
+            .unwrap();
+        m.assert();
+
+        assert_eq!("123456789012", actual);
+    }
+
+    #[tokio::test]
+    async fn test_aws_ec2_metadata_listing() {
+        let m = mock("GET", "/iam/security-credentials/")
+            .with_status(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body("first-role\nsecond-role")
+            .expect(2)
+            .create();
+
+        let url = mockito::server_url();
+        let loader = AwsEc2MetadataLoader::with_urls(&url, &url);
+
+        let all = loader
+            .load("meta-data/iam/security-credentials/")
+            .await
+            .unwrap();
+        let second = loader.load("iam/security-credentials/#1").await.unwrap();
+        m.assert();
+
+        assert_eq!("first-role,second-role", all);
+        assert_eq!("second-role", second);
+    }
+
+    #[test]
+    fn test_with_base_url() {
+        let loader = AwsEc2MetadataLoader::with_base_url("http://localhost:1338/latest/meta-data/");
+        assert_eq!("http://localhost:1338/latest/dynamic", loader.dynamic_url);
+
+        let loader = AwsEc2MetadataLoader::with_base_url("http://localhost:1338/");
+        assert_eq!("http://localhost:1338/", loader.dynamic_url);
+    }
+
     #[tokio::test]
     async fn test_get_current_region() {
         let az = "us-east-1a";
@@ -1,68 +1,91 @@
 //! Provides the ability to asynchronously load values from [AWS EC2 Tags](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/Using_Tags.html)
+//!
+//! Tags are read from the [instance metadata tags endpoint](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/Using_Tags.html#work-with-tags-in-IMDS)
+//! when instance metadata tags are enabled, which doesn't require any IAM permissions. Otherwise
+//! they are loaded using the EC2 `DescribeInstances` API, which requires the
+//! `ec2:DescribeInstances` permission. See [`Ec2TagMode`](enum.Ec2TagMode.html)
+use crate::loader::aws::{api_error, AwsConfig};
+use crate::NotFound;
 use anyhow::{anyhow, Result};
-use rusoto_core::Region;
 use rusoto_ec2::{DescribeInstancesRequest, Ec2, Ec2Client, Tag};
+use std::str::FromStr;
 
 pub(crate) const TEMPLATE_KEY: &str = "awsec2tag";
 
+/// Controls where the `awsec2tag` source reads the instance's tags from
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Ec2TagMode {
+    /// Read the tags from the instance metadata tags endpoint if instance metadata tags are
+    /// enabled, otherwise fall back to the EC2 API. Any other metadata service error fails
+    /// initialisation rather than falling back
+    #[default]
+    Auto,
+    /// Only read the tags from the instance metadata tags endpoint
+    Metadata,
+    /// Only read the tags using the EC2 `DescribeInstances` API
+    Api,
+}
+
+impl FromStr for Ec2TagMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "metadata" => Ok(Self::Metadata),
+            "api" => Ok(Self::Api),
+            _ => Err(anyhow!("Invalid EC2 tag mode '{}'", s)),
+        }
+    }
+}
+
 /// This type provides functionality for loading values from [AWS EC2 Tags](https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/Using_Tags.html)
 pub struct AwsEc2TagLoader {
+    config: AwsConfig,
+    client: Option<Ec2Client>,
+    mode: Ec2TagMode,
+    metadata_url: String,
     tags: Vec<Tag>,
 }
 
 impl AwsEc2TagLoader {
-    /// Creates a new AwsEc2TagLoader with the default region
-    pub async fn new() -> Result<Self> {
-        // This will attempt to read AWS_DEFAULT_REGION and AWS_REGION from the environment. If
-        // neither are set, it will fallback to us-east-1
-        let region: Region = crate::loader::awsec2metadata::get_current_region()
-            .await?
-            .parse()
-            .unwrap_or_default();
-        let client = Ec2Client::new(region);
-        Self::with_client(client).await
+    /// Creates a new AwsEc2TagLoader from the given configuration, reading the tags from the
+    /// source selected by the given mode. The tags are loaded when the loader is initialised
+    pub fn new(config: &AwsConfig, mode: Ec2TagMode) -> Self {
+        Self {
+            config: config.clone(),
+            client: None,
+            mode,
+            metadata_url: config.metadata_url(),
+            tags: Vec::new(),
+        }
     }
 
-    /// Creates a new AwsEc2TagLoader with the provided Ec2Client
-    pub async fn with_client(client: Ec2Client) -> Result<Self> {
-        Self::with_client_and_metadata_url(client, crate::loader::awsec2metadata::METADATA_BASE_URL)
-            .await
-    }
-
-    /// Creates a new AwsEc2TagLoader with the provided Ec2Client and metadata URL
-    pub async fn with_client_and_metadata_url(
+    /// Creates a new AwsEc2TagLoader with the provided Ec2Client, mode and metadata URL
+    #[cfg(test)]
+    pub fn with_client_and_metadata_url(
         client: Ec2Client,
+        mode: Ec2TagMode,
         metadata_url: &str,
-    ) -> Result<Self> {
-        let instance_id =
-            crate::loader::awsec2metadata::get_metadata_value(metadata_url, "instance-id").await?;
-
-        let req = DescribeInstancesRequest {
-            instance_ids: Some(vec![instance_id]),
-            ..Default::default()
-        };
-
-        let response = match client.describe_instances(req).await {
-            Ok(response) => response,
-            Err(e) => return Err(anyhow!("Failed to fetch tag value: {}", e)),
-        };
-
-        let tags = response
-            .reservations
-            .ok_or_else(|| anyhow!("Reservations missing from response"))?
-            .first()
-            .ok_or_else(|| anyhow!("No Reservations found"))?
-            .instances
-            .as_ref()
-            .ok_or_else(|| anyhow!("Instances missing from response"))?
-            .first()
-            .ok_or_else(|| anyhow!("No Instances found"))?
-            .tags
-            .as_ref()
-            .ok_or_else(|| anyhow!("Tags missing from response"))?
-            .clone();
+    ) -> Self {
+        Self {
+            config: AwsConfig::default(),
+            client: Some(client),
+            mode,
+            metadata_url: metadata_url.to_string(),
+            tags: Vec::new(),
+        }
+    }
 
-        Ok(Self { tags })
+    /// Loads the tags using the EC2 API, creating a client if one wasn't provided
+    async fn get_tags_from_api(&self) -> Result<Vec<Tag>> {
+        match &self.client {
+            Some(client) => get_tags_from_api(client, &self.metadata_url).await,
+            None => {
+                let client = self.config.client(Ec2Client::new_with).await?;
+                get_tags_from_api(&client, &self.metadata_url).await
+            }
+        }
     }
 
     /// Loads an EC2 tag value by it's key and returns it as a `String`
@@ -73,7 +96,7 @@ impl AwsEc2TagLoader {
             .filter(|t| t.key.as_ref().unwrap_or(&"".into()).to_lowercase() == key.to_lowercase())
             .collect::<Vec<&rusoto_ec2::Tag>>()
             .first()
-            .ok_or_else(|| anyhow!("Tag with key '{}' not found", key))?
+            .ok_or_else(|| NotFound::new(format!("Tag with key '{}' not found", key)))?
             .value
             .as_ref()
             .ok_or_else(|| anyhow!("Tag has no value"))?
@@ -83,8 +106,95 @@ impl AwsEc2TagLoader {
     }
 }
 
+/// Loads the instance's tags from the instance metadata tags endpoint, or returns None if
+/// instance metadata tags are disabled
+async fn get_tags_from_metadata(metadata_url: &str) -> Result<Option<Vec<Tag>>> {
+    let keys =
+        match crate::loader::awsec2metadata::get_metadata_value(metadata_url, "tags/instance/")
+            .await
+        {
+            Ok(keys) => keys,
+            // The tags endpoint doesn't exist when instance metadata tags are disabled
+            Err(e) if NotFound::is(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+    let mut tags = Vec::new();
+    for key in keys.lines().map(str::trim).filter(|k| !k.is_empty()) {
+        let value = match crate::loader::awsec2metadata::get_metadata_value(
+            metadata_url,
+            &format!("tags/instance/{}", key),
+        )
+        .await
+        {
+            Ok(value) => value,
+            // The tag was removed after the keys were listed
+            Err(e) if NotFound::is(&e) => continue,
+            Err(e) => return Err(e),
+        };
+
+        tags.push(Tag {
+            key: Some(key.to_string()),
+            value: Some(value),
+        });
+    }
+
+    Ok(Some(tags))
+}
+
+/// Loads the instance's tags using the EC2 `DescribeInstances` API
+async fn get_tags_from_api(client: &Ec2Client, metadata_url: &str) -> Result<Vec<Tag>> {
+    let instance_id =
+        crate::loader::awsec2metadata::get_metadata_value(metadata_url, "instance-id").await?;
+
+    let req = DescribeInstancesRequest {
+        instance_ids: Some(vec![instance_id]),
+        ..Default::default()
+    };
+
+    let response = match client.describe_instances(req).await {
+        Ok(response) => response,
+        Err(e) => return Err(api_error("Failed to fetch tag value", e)),
+    };
+
+    let tags = response
+        .reservations
+        .ok_or_else(|| anyhow!("Reservations missing from response"))?
+        .first()
+        .ok_or_else(|| anyhow!("No Reservations found"))?
+        .instances
+        .as_ref()
+        .ok_or_else(|| anyhow!("Instances missing from response"))?
+        .first()
+        .ok_or_else(|| anyhow!("No Instances found"))?
+        .tags
+        .as_ref()
+        .ok_or_else(|| anyhow!("Tags missing from response"))?
+        .clone();
+
+    Ok(tags)
+}
+
 #[async_trait::async_trait]
 impl crate::Loader for AwsEc2TagLoader {
+    /// Loads the instance's tags from the source selected by the mode
+    async fn init(&mut self) -> Result<()> {
+        self.tags = match self.mode {
+            Ec2TagMode::Api => self.get_tags_from_api().await?,
+            Ec2TagMode::Metadata => get_tags_from_metadata(&self.metadata_url)
+                .await?
+                .ok_or_else(|| anyhow!("Instance metadata tags are disabled for this instance"))?,
+            // Only fall back to the API when instance metadata tags are disabled. Other errors,
+            // such as the metadata service being unavailable, are returned so they can be retried
+            Ec2TagMode::Auto => match get_tags_from_metadata(&self.metadata_url).await? {
+                Some(tags) => tags,
+                None => self.get_tags_from_api().await?,
+            },
+        };
+
+        Ok(())
+    }
+
     /// Loads a value from the EC2 Instance's Tags and returns it as a `String`
     async fn load(&self, key: &str) -> Result<String> {
         self.get_tag_value(key).await
@@ -94,7 +204,7 @@ impl crate::Loader for AwsEc2TagLoader {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::Loader;
+    use crate::{Loader, Retryable};
     use rusoto_mock::{
         MockCredentialsProvider, MockRequestDispatcher, MockResponseReader, ReadMockResponse,
     };
@@ -122,9 +232,9 @@ mod test {
             .create();
 
         let url = &mockito::server_url();
-        let loader = AwsEc2TagLoader::with_client_and_metadata_url(mock_client, url)
-            .await
-            .unwrap();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Api, url);
+        loader.init().await.unwrap();
         let actual = loader.load("TestTag").await.unwrap();
 
         m.assert();
@@ -150,15 +260,139 @@ mod test {
             .create();
 
         let url = &mockito::server_url();
-        let loader = AwsEc2TagLoader::with_client_and_metadata_url(mock_client, url)
-            .await
-            .unwrap();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Api, url);
+        loader.init().await.unwrap();
         let actual = loader.load("testtag").await.unwrap();
 
         m.assert();
         assert_eq!(tag_value(), actual);
     }
 
+    #[tokio::test]
+    async fn test_aws_ec2_tag_load_from_metadata() {
+        // The dispatcher returns an error for any request so the tags must come from the
+        // metadata service
+        let mock_client = rusoto_ec2::Ec2Client::new_with(
+            MockRequestDispatcher::with_status(403),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let keys = mockito::mock("GET", "/tags/instance/")
+            .with_status(200)
+            .with_body("Name\nTestTag")
+            .create();
+        let name = mockito::mock("GET", "/tags/instance/Name")
+            .with_status(200)
+            .with_body("my-instance")
+            .create();
+        let test_tag = mockito::mock("GET", "/tags/instance/TestTag")
+            .with_status(200)
+            .with_body(tag_value())
+            .create();
+
+        let url = &mockito::server_url();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Auto, url);
+        loader.init().await.unwrap();
+
+        keys.assert();
+        name.assert();
+        test_tag.assert();
+        assert_eq!(tag_value(), loader.load("testtag").await.unwrap());
+        assert_eq!("my-instance", loader.load("Name").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_aws_ec2_tag_auto_falls_back_to_api() {
+        let mock_client = rusoto_ec2::Ec2Client::new_with(
+            MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                "testdata/awsec2tag",
+                // Taken from https://github.com/rusoto/rusoto/tree/master/rusoto/services/ec2/test_resources/generated
+                "describe-instances-response.xml",
+            )),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        // Instance metadata tags are disabled
+        let keys = mockito::mock("GET", "/tags/instance/")
+            .with_status(404)
+            .create();
+        let instance_id = mockito::mock("GET", "/instance-id")
+            .with_status(200)
+            .with_body("i-01234567890123456")
+            .create();
+
+        let url = &mockito::server_url();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Auto, url);
+        loader.init().await.unwrap();
+
+        keys.assert();
+        instance_id.assert();
+        assert_eq!(tag_value(), loader.load("TestTag").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_aws_ec2_tag_auto_only_falls_back_when_disabled() {
+        let mock_client = rusoto_ec2::Ec2Client::new_with(
+            MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                "testdata/awsec2tag",
+                // Taken from https://github.com/rusoto/rusoto/tree/master/rusoto/services/ec2/test_resources/generated
+                "describe-instances-response.xml",
+            )),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        // The metadata service is struggling, so the API mustn't be used
+        let keys = mockito::mock("GET", "/tags/instance/")
+            .with_status(503)
+            .create();
+        let instance_id = mockito::mock("GET", "/instance-id")
+            .with_status(200)
+            .with_body("i-01234567890123456")
+            .expect(0)
+            .create();
+
+        let url = &mockito::server_url();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Auto, url);
+        let err = loader.init().await.unwrap_err();
+
+        keys.assert();
+        instance_id.assert();
+        assert!(Retryable::is(&err));
+
+        // A tag removed after the keys were listed is skipped rather than falling back
+        let mock_client = rusoto_ec2::Ec2Client::new_with(
+            MockRequestDispatcher::with_status(403),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+        let _keys = mockito::mock("GET", "/tags/instance/")
+            .with_status(200)
+            .with_body("Name\nRemoved")
+            .create();
+        let _name = mockito::mock("GET", "/tags/instance/Name")
+            .with_status(200)
+            .with_body("my-instance")
+            .create();
+        let _removed = mockito::mock("GET", "/tags/instance/Removed")
+            .with_status(404)
+            .create();
+
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Auto, url);
+        loader.init().await.unwrap();
+
+        instance_id.assert();
+        assert_eq!("my-instance", loader.load("Name").await.unwrap());
+        assert!(NotFound::is(&loader.load("Removed").await.unwrap_err()));
+    }
+
     #[tokio::test]
     async fn test_aws_ec2_tag_load_caches_tags() {
         let mock_client = rusoto_ec2::Ec2Client::new_with(
@@ -180,9 +414,9 @@ mod test {
             .create();
 
         let url = &mockito::server_url();
-        let loader = AwsEc2TagLoader::with_client_and_metadata_url(mock_client, url)
-            .await
-            .unwrap();
+        let mut loader =
+            AwsEc2TagLoader::with_client_and_metadata_url(mock_client, Ec2TagMode::Api, url);
+        loader.init().await.unwrap();
         assert_eq!(tag_value(), loader.load("TestTag").await.unwrap());
         assert_eq!(tag_value(), loader.load("TestTag").await.unwrap());
         assert_eq!(tag_value(), loader.load("TestTag").await.unwrap());
@@ -0,0 +1,185 @@
+//! Provides the ability to asynchronously load values from the [AWS ECS Task Metadata Endpoint](https://docs.aws.amazon.com/AmazonECS/latest/developerguide/task-metadata-endpoint-v4.html)
+//!
+//! Keys take the form `<path>#<field>` where the path is either `container`, `task`, `stats` or
+//! `task/stats` and the field is a dot separated path into the JSON document returned by the
+//! endpoint.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut seed = germinate::Seed::new("Cluster: %awsecs:task#Cluster%, Container: %awsecs:container#Name%");
+//! let output = seed.germinate().await.unwrap();
+//! assert_eq!(String::from("Cluster: default, Container: app"), output);
+//! ```
+use crate::loader::aws::is_retryable_status;
+use crate::loader::json;
+use crate::loader::uninitialised;
+use crate::{Retryable, Value};
+use anyhow::{anyhow, Result};
+
+pub(crate) const TEMPLATE_KEY: &str = "awsecs";
+
+/// The environment variable ECS sets to the metadata endpoint of the container
+pub(crate) const METADATA_URL_VAR: &str = "ECS_CONTAINER_METADATA_URI_V4";
+
+/// This type provides functionality for loading values from the [AWS ECS Task Metadata Endpoint](https://docs.aws.amazon.com/AmazonECS/latest/developerguide/task-metadata-endpoint-v4.html)
+pub struct AwsEcsLoader {
+    metadata_url: Option<String>,
+}
+
+impl AwsEcsLoader {
+    /// Creates a new AwsEcsLoader using the endpoint ECS provides to the container
+    pub fn new() -> Self {
+        Self {
+            metadata_url: std::env::var(METADATA_URL_VAR).ok(),
+        }
+    }
+
+    /// Creates a new AwsEcsLoader with the provided metadata endpoint
+    #[cfg(test)]
+    pub fn with_metadata_url(url: &str) -> Self {
+        Self {
+            metadata_url: Some(url.trim_end_matches('/').to_string()),
+        }
+    }
+}
+
+/// Loads the JSON document at the given path relative to the metadata endpoint
+async fn get_metadata_document(base_url: &str, path: &str) -> Result<serde_json::Value> {
+    let path = match path.trim_matches('/') {
+        "container" | "" => String::new(),
+        path => format!("/{}", path),
+    };
+
+    let mut response = surf::get(format!("{}{}", base_url, path))
+        .await
+        .map_err(|e| {
+            anyhow::Error::new(Retryable::new(e.to_string()))
+                .context("Failed to load task metadata")
+        })?;
+
+    if !response.status().is_success() {
+        let message = format!(
+            "Task metadata endpoint responded with status {} for '{}'",
+            response.status(),
+            path
+        );
+        return Err(match is_retryable_status(response.status().into()) {
+            true => Retryable::new(message).into(),
+            false => anyhow!(message),
+        });
+    }
+
+    response
+        .body_json()
+        .await
+        .map_err(|e| anyhow!("{}", e).context("Failed to decode task metadata"))
+}
+
+/// Loads the region of the task from the ARN in the task metadata
+pub(crate) async fn get_current_region_from_url(base_url: &str) -> Result<String> {
+    let task = get_metadata_document(base_url, "task").await?;
+    let arn = task
+        .get("TaskARN")
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| anyhow!("TaskARN missing from task metadata"))?;
+
+    // Task ARNs take the form arn:aws:ecs:<region>:<account>:task/...
+    arn.split(':')
+        .nth(3)
+        .filter(|r| !r.is_empty())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("Failed to parse region from task ARN '{}'", arn))
+}
+
+#[async_trait::async_trait]
+impl crate::Loader for AwsEcsLoader {
+    /// Checks that the metadata endpoint is available
+    async fn init(&mut self) -> Result<()> {
+        match self.metadata_url {
+            Some(_) => Ok(()),
+            None => Err(anyhow!(
+                "{} isn't set, make sure germinate is running in an ECS task",
+                METADATA_URL_VAR
+            )),
+        }
+    }
+
+    /// Loads a field from the task metadata and returns it as a `String`
+    async fn load(&self, key: &str) -> Result<String> {
+        Ok(self.load_value(key).await?.to_string())
+    }
+
+    /// Loads a field from the task metadata, keeping it's structure, e.g. `task#Containers`
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        let (path, selector) = json::split_key(key);
+        let base_url = self.metadata_url.as_deref().ok_or_else(uninitialised)?;
+        let document = get_metadata_document(base_url, path).await?;
+
+        Ok(Value::from(document)
+            .select(selector.unwrap_or_default())?
+            .clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Loader;
+    use mockito::mock;
+
+    fn task_metadata() -> &'static str {
+        r#"{
+            "Cluster": "default",
+            "TaskARN": "arn:aws:ecs:eu-west-1:111122223333:task/default/158d1c8083dd49d6b527399fd6414f5c",
+            "AvailabilityZone": "eu-west-1a",
+            "Containers": [{"Name": "app", "DockerId": "abc123"}]
+        }"#
+    }
+
+    #[tokio::test]
+    async fn test_aws_ecs_load_task_fields() {
+        let m = mock("GET", "/task")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(task_metadata())
+            .expect(2)
+            .create();
+
+        let loader = AwsEcsLoader::with_metadata_url(&mockito::server_url());
+
+        assert_eq!("default", loader.load("task#Cluster").await.unwrap());
+        assert_eq!("app", loader.load("task#Containers.0.Name").await.unwrap());
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn test_aws_ecs_load_container_fields() {
+        let m = mock("GET", "/")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(r#"{"Name": "app", "Labels": {"com.amazonaws.ecs.cluster": "default"}}"#)
+            .create();
+
+        let loader = AwsEcsLoader::with_metadata_url(&format!("{}/", mockito::server_url()));
+
+        assert_eq!("app", loader.load("container#Name").await.unwrap());
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_current_region() {
+        let m = mock("GET", "/task")
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(task_metadata())
+            .create();
+
+        let actual = get_current_region_from_url(&mockito::server_url())
+            .await
+            .unwrap();
+        m.assert();
+
+        assert_eq!("eu-west-1", actual);
+    }
+}
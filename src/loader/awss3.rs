@@ -0,0 +1,402 @@
+//! Provides the ability to asynchronously load the contents of objects stored in [AWS S3](https://docs.aws.amazon.com/AmazonS3/latest/userguide/Welcome.html)
+//!
+//! Keys take the form `<bucket>/<key>`, optionally followed by query parameters to select a
+//! specific version of the object (`?versionId=<id>`) or raise the size limit for it
+//! (`?maxSize=<bytes>`). Objects larger than the limit fail to load rather than being inlined.
+//! A field can be selected from objects containing JSON with a `#` suffix. Object keys which
+//! contain `?` or `#` can be written by escaping them with a backslash, e.g.
+//! `my-bucket/reports/q1\?draft.txt`, and a literal backslash followed by either is written as
+//! `\\`.
+//!
+//! Buckets in a different region to the client are followed using the region S3 returns when it
+//! redirects the request, or looked up with `GetBucketLocation` when it doesn't.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut seed = germinate::Seed::new("%awss3:my-bucket/certs/server.pem?versionId=3HL4kqtJlcpXroDTDmJ%");
+//! let output = seed.germinate().await.unwrap();
+//!
+//! let mut seed = germinate::Seed::new("%awss3:my-bucket/config.json#database.host%");
+//! let output = seed.germinate().await.unwrap();
+//! ```
+use crate::loader::aws::{api_error, AwsConfig};
+use crate::loader::uninitialised;
+use crate::registry::lock;
+use crate::{NotFound, Value};
+use anyhow::{anyhow, Context, Result};
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetBucketLocationRequest, GetObjectError, GetObjectRequest, S3Client, S3};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::AsyncReadExt;
+
+pub(crate) const TEMPLATE_KEY: &str = "awss3";
+
+/// The largest object that will be loaded unless a larger limit is given in the key
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// This type provides functionality for loading the contents of objects stored in [AWS S3](https://docs.aws.amazon.com/AmazonS3/latest/userguide/Welcome.html)
+pub struct AwsS3Loader {
+    config: AwsConfig,
+    client: Option<S3Client>,
+    /// Clients for buckets in other regions, by region name
+    regional_clients: Mutex<HashMap<String, S3Client>>,
+}
+
+/// The location of an object and the options for loading it, parsed from a template key
+#[derive(Debug, PartialEq)]
+struct ObjectRef<'k> {
+    bucket: &'k str,
+    key: Cow<'k, str>,
+    version_id: Option<&'k str>,
+    max_size: u64,
+    path: Option<&'k str>,
+}
+
+impl<'k> ObjectRef<'k> {
+    fn parse(key: &'k str) -> Result<Self> {
+        let (location, path) = match find_unescaped(key, '#') {
+            Some(i) => (&key[..i], Some(&key[i + 1..])),
+            None => (key, None),
+        };
+        let (location, query) = match find_unescaped(location, '?') {
+            Some(i) => (&location[..i], Some(&location[i + 1..])),
+            None => (location, None),
+        };
+
+        let (bucket, object_key) = location
+            .split_once('/')
+            .filter(|(b, k)| !b.is_empty() && !k.is_empty())
+            .ok_or_else(|| anyhow!("Invalid S3 location '{}', expected bucket/key", location))?;
+
+        let mut object = Self {
+            bucket,
+            key: unescape(object_key),
+            version_id: None,
+            max_size: DEFAULT_MAX_SIZE,
+            path,
+        };
+
+        for param in query.into_iter().flat_map(|q| q.split('&')) {
+            match param.split_once('=') {
+                Some(("versionId", id)) => object.version_id = Some(id),
+                Some(("maxSize", size)) => {
+                    object.max_size = size
+                        .parse()
+                        .with_context(|| anyhow!("Invalid maxSize '{}'", size))?
+                }
+                _ => return Err(anyhow!("Unsupported S3 parameter '{}'", param)),
+            }
+        }
+
+        Ok(object)
+    }
+}
+
+/// The characters which can be escaped with a backslash in an object key
+const ESCAPABLE: &[char] = &['?', '#', '\\'];
+
+/// Returns the index of the first occurrence of the character which isn't escaped
+fn find_unescaped(key: &str, c: char) -> Option<usize> {
+    let mut chars = key.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if chars
+                .peek()
+                .is_some_and(|(_, next)| ESCAPABLE.contains(next)) =>
+            {
+                chars.next();
+            }
+            ch if ch == c => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Removes the backslashes escaping characters in an object key
+fn unescape(key: &str) -> Cow<'_, str> {
+    if !key.contains('\\') {
+        return Cow::Borrowed(key);
+    }
+
+    let mut unescaped = String::with_capacity(key.len());
+    let mut chars = key.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('\\', Some(next)) if ESCAPABLE.contains(next) => {}
+            _ => unescaped.push(ch),
+        }
+    }
+
+    Cow::Owned(unescaped)
+}
+
+/// Returns the region S3 says a bucket is in when a request was sent to the wrong region
+fn redirect_region(error: &RusotoError<GetObjectError>) -> Option<Option<String>> {
+    match error {
+        RusotoError::Unknown(response) if matches!(response.status.as_u16(), 301 | 307 | 400) => {
+            let region = response.headers.get("x-amz-bucket-region").cloned();
+            // A 400 is only a region error when S3 says which region the bucket is in
+            match (response.status.as_u16(), region) {
+                (400, None) => None,
+                (_, region) => Some(region),
+            }
+        }
+        _ => None,
+    }
+}
+
+impl AwsS3Loader {
+    /// Creates a new AwsS3Loader from the given configuration. The client is created when the
+    /// loader is initialised
+    pub fn new(config: &AwsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: None,
+            regional_clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a new AwsS3Loader with the provided S3Client
+    #[cfg(test)]
+    pub fn with_client(client: S3Client) -> Self {
+        Self {
+            config: AwsConfig::default(),
+            client: Some(client),
+            regional_clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the client for buckets in the given region, creating it on first use
+    fn regional_client(&self, region: &str) -> Result<S3Client> {
+        let mut clients = lock(&self.regional_clients);
+        if let Some(client) = clients.get(region) {
+            return Ok(client.clone());
+        }
+
+        let client = self.config.client_in_region(region, S3Client::new_with)?;
+        clients.insert(region.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// Looks up the region a bucket is in
+    async fn bucket_region(&self, client: &S3Client, bucket: &str) -> Result<String> {
+        let req = GetBucketLocationRequest {
+            bucket: bucket.to_string(),
+            ..Default::default()
+        };
+
+        let response = client
+            .get_bucket_location(req)
+            .await
+            .map_err(|e| api_error("Failed to find the region of the bucket", e))?;
+
+        // Buckets in us-east-1 have no location constraint, and `EU` is the legacy name of
+        // eu-west-1
+        Ok(match response.location_constraint.as_deref() {
+            None | Some("") => String::from("us-east-1"),
+            Some("EU") => String::from("eu-west-1"),
+            Some(region) => region.to_string(),
+        })
+    }
+
+    /// Loads the contents of an object, failing if it's larger than the given size. Requests for
+    /// buckets in another region are sent again to that region
+    async fn get_object(&self, object: &ObjectRef<'_>) -> Result<Vec<u8>> {
+        let req = GetObjectRequest {
+            bucket: object.bucket.to_string(),
+            key: object.key.to_string(),
+            version_id: object.version_id.map(String::from),
+            ..Default::default()
+        };
+
+        let client = self.client.as_ref().ok_or_else(uninitialised)?;
+
+        let result = match client.get_object(req.clone()).await {
+            Err(e) => match redirect_region(&e) {
+                Some(region) => {
+                    let region = match region {
+                        Some(region) => region,
+                        None => self.bucket_region(client, object.bucket).await?,
+                    };
+                    self.regional_client(&region)?.get_object(req).await
+                }
+                None => Err(e),
+            },
+            result => result,
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                return Err(anyhow::Error::new(NotFound::new(format!(
+                    "Object not found 's3://{}/{}'",
+                    object.bucket, object.key
+                )))
+                .context("Failed to fetch object from AWS S3"))
+            }
+            Err(e) => return Err(api_error("Failed to fetch object", e)),
+        };
+
+        let too_large = || {
+            anyhow!(
+                "Object 's3://{}/{}' is larger than the limit of {} bytes",
+                object.bucket,
+                object.key,
+                object.max_size
+            )
+        };
+
+        if response.content_length.unwrap_or_default() as u64 > object.max_size {
+            return Err(too_large());
+        }
+
+        let body = response.body.ok_or_else(|| anyhow!("Object has no body"))?;
+
+        // The content length isn't always set, so only read up to one byte more than the limit
+        let mut contents = Vec::new();
+        body.into_async_read()
+            .take(object.max_size + 1)
+            .read_to_end(&mut contents)
+            .await
+            .context("Failed to read object body")?;
+
+        if contents.len() as u64 > object.max_size {
+            return Err(too_large());
+        }
+
+        Ok(contents)
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Loader for AwsS3Loader {
+    /// Creates the S3Client if one wasn't provided
+    async fn init(&mut self) -> Result<()> {
+        if self.client.is_none() {
+            self.client = Some(self.config.client(S3Client::new_with).await?);
+        }
+
+        Ok(())
+    }
+
+    /// Loads the contents of an object and returns it as a `String`. If the key has a `#` suffix,
+    /// the object is parsed as JSON and the field at the following path is returned
+    async fn load(&self, key: &str) -> Result<String> {
+        match self.load_value(key).await? {
+            Value::Bytes(_) => Err(anyhow!("Object is not valid UTF-8")),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    /// Loads the contents of an object, keeping the structure of fields selected with a `#`
+    /// suffix. Objects which aren't valid UTF-8 are returned as bytes
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        let object = ObjectRef::parse(key)?;
+        let contents = self.get_object(&object).await?;
+
+        match object.path {
+            Some(path) => {
+                let document: serde_json::Value =
+                    serde_json::from_slice(&contents).context("Object is not a JSON document")?;
+                Ok(Value::from(document).select(path)?.clone())
+            }
+            None => Ok(match String::from_utf8(contents) {
+                Ok(contents) => Value::String(contents),
+                Err(e) => Value::Bytes(e.into_bytes()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Loader;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+
+    fn client_with_body(body: &str) -> S3Client {
+        S3Client::new_with(
+            MockRequestDispatcher::default().with_body(body),
+            MockCredentialsProvider,
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_parse_object_ref() {
+        assert_eq!(
+            ObjectRef {
+                bucket: "bucket",
+                key: "path/to/config.json".into(),
+                version_id: Some("abc"),
+                max_size: 10,
+                path: Some("db.host"),
+            },
+            ObjectRef::parse("bucket/path/to/config.json?versionId=abc&maxSize=10#db.host")
+                .unwrap()
+        );
+        assert!(ObjectRef::parse("bucket").is_err());
+        assert!(ObjectRef::parse("bucket/key?unknown=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_object_ref_escapes() {
+        let object = ObjectRef::parse(r"bucket/what\?\#1\\?versionId=abc#a.b").unwrap();
+        assert_eq!(r"what?#1\", object.key);
+        assert_eq!(Some("abc"), object.version_id);
+        assert_eq!(Some("a.b"), object.path);
+
+        // Other backslashes are part of the key
+        let object = ObjectRef::parse(r"bucket/a\b").unwrap();
+        assert_eq!(r"a\b", object.key);
+    }
+
+    #[tokio::test]
+    async fn test_s3_load_object_in_other_region() {
+        let redirect = S3Client::new_with(
+            MockRequestDispatcher::with_status(301).with_header("x-amz-bucket-region", "eu-west-2"),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+        let loader = AwsS3Loader::with_client(redirect);
+        lock(&loader.regional_clients)
+            .insert("eu-west-2".into(), client_with_body("object contents"));
+
+        assert_eq!(
+            "object contents",
+            loader.load("bucket/key.txt").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_load_object() {
+        let loader = AwsS3Loader::with_client(client_with_body("object contents"));
+        let actual = loader.load("bucket/key.txt").await.unwrap();
+
+        assert_eq!("object contents", actual);
+    }
+
+    #[tokio::test]
+    async fn test_s3_load_json_field() {
+        let loader = AwsS3Loader::with_client(client_with_body(r#"{"db": {"host": "db.local"}}"#));
+        let actual = loader.load("bucket/config.json#db.host").await.unwrap();
+
+        assert_eq!("db.local", actual);
+    }
+
+    #[tokio::test]
+    async fn test_s3_load_object_too_large() {
+        let loader = AwsS3Loader::with_client(client_with_body("object contents"));
+        let actual = loader.load("bucket/key.txt?maxSize=5").await;
+
+        match actual {
+            Err(err) => assert!(format!("{:?}", err).contains("larger than the limit")),
+            _ => panic!(),
+        }
+    }
+}
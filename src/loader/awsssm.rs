@@ -8,52 +8,62 @@
 //! let output = seed.germinate().await.unwrap();
 //! assert_eq!(String::from("SSM template: ssm value"), output);
 //! ```
+use crate::loader::aws::{api_error, AwsConfig};
+use crate::loader::uninitialised;
+use crate::{NotFound, Secret, Value};
 use anyhow::{anyhow, Result};
-use rusoto_core::Region;
 use rusoto_ssm::{GetParameterRequest, Ssm, SsmClient};
 
 pub(crate) const TEMPLATE_KEY: &str = "awsssm";
 
 /// This type provides functionality for loading values from [AWS Systems Manager Parameter Store](https://docs.aws.amazon.com/systems-manager/latest/userguide/systems-manager-parameter-store.html)
 pub struct AwsSsmLoader {
-    client: rusoto_ssm::SsmClient,
+    config: AwsConfig,
+    client: Option<SsmClient>,
 }
 
 impl AwsSsmLoader {
-    /// Creates a new AwsSsmLoader with the default region
-    pub async fn new() -> Result<Self> {
-        // This will attempt to read AWS_DEFAULT_REGION and AWS_REGION from the environment. If
-        // neither are set, it will fallback to us-east-1
-        let region: Region = crate::loader::awsec2metadata::get_current_region()
-            .await?
-            .parse()
-            .unwrap_or_default();
-        let client = SsmClient::new(region);
-        Ok(Self::with_client(client))
+    /// Creates a new AwsSsmLoader from the given configuration. The client is created when the
+    /// loader is initialised
+    pub fn new(config: &AwsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: None,
+        }
     }
 
     /// Creates a new AwsSsmLoader with the provided SsmClient
+    #[cfg(test)]
     pub fn with_client(client: SsmClient) -> Self {
-        Self { client }
+        Self {
+            config: AwsConfig::default(),
+            client: Some(client),
+        }
     }
 
-    /// Loads a parameter from the Parameter Store and returns it as a `String`. Provides the
-    /// `decrypt` argument to control whether or not the value should be decrypted
-    async fn get_parameter(&self, name: &str, decrypt: bool) -> Result<String> {
+    /// Loads a parameter from the Parameter Store and returns it as a `Value`. Provides the
+    /// `decrypt` argument to control whether or not the value should be decrypted. `StringList`
+    /// parameters are returned as a list and `SecureString` parameters as a secret
+    async fn get_parameter(&self, name: &str, decrypt: bool) -> Result<Value> {
         let req = GetParameterRequest {
             name: name.to_string(),
             with_decryption: Some(decrypt),
         };
 
-        let response = match self.client.get_parameter(req).await {
+        let client = self.client.as_ref().ok_or_else(uninitialised)?;
+
+        let response = match client.get_parameter(req).await {
             Ok(response) => response,
             Err(rusoto_core::RusotoError::Service(
                 rusoto_ssm::GetParameterError::ParameterNotFound(_),
             )) => {
-                return Err(anyhow!("Parameter not found '{}'", name)
-                    .context("Failed to fetch parameter from AWS SSM"))
+                return Err(anyhow::Error::new(NotFound::new(format!(
+                    "Parameter not found '{}'",
+                    name
+                )))
+                .context("Failed to fetch parameter from AWS SSM"))
             }
-            Err(e) => return Err(anyhow!("Failed to fetch parameter: {}", e)),
+            Err(e) => return Err(api_error("Failed to fetch parameter", e)),
         };
 
         let parameter = response
@@ -64,14 +74,35 @@ impl AwsSsmLoader {
             .value
             .ok_or_else(|| anyhow!("Parameter has no value"))?;
 
-        Ok(value)
+        match parameter.type_.as_deref() {
+            Some("StringList") => Ok(Value::List(Value::String(value).into_list())),
+            Some("SecureString") => Ok(Value::Secret(Secret::from(value))),
+            _ => Ok(Value::String(value)),
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl crate::Loader for AwsSsmLoader {
-    /// Loads a value from the Parameter Store and returns it as a `String`
+    /// Creates the SsmClient if one wasn't provided
+    async fn init(&mut self) -> Result<()> {
+        if self.client.is_none() {
+            self.client = Some(self.config.client(SsmClient::new_with).await?);
+        }
+
+        Ok(())
+    }
+
+    /// Loads a value from the Parameter Store and returns it as a `String`. The values of
+    /// `SecureString` parameters are returned in full, and the string isn't zeroed when it's
+    /// dropped
     async fn load(&self, key: &str) -> Result<String> {
+        Ok(self.load_value(key).await?.expose())
+    }
+
+    /// Loads a value from the Parameter Store, returning `StringList` parameters as a list and
+    /// marking `SecureString` parameters as secret
+    async fn load_value(&self, key: &str) -> Result<Value> {
         // TODO hard coded decrypt value
         // Options:
         //   flag --awsssm-decrypt - will only work if all values are encrypted
@@ -107,6 +138,62 @@ mod test {
         assert_eq!(String::from("ssm value"), actual);
     }
 
+    #[tokio::test]
+    async fn test_ssm_load_string_list_parameter() {
+        let mock_client = rusoto_ssm::SsmClient::new_with(
+            MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                "testdata/awsssm",
+                "get-parameter-string-list-response.json",
+            )),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let loader = AwsSsmLoader::with_client(mock_client);
+        let actual = loader.load_value("test.param").await.unwrap();
+
+        assert_eq!(
+            Value::List(vec!["a".into(), "b".into(), "c".into()]),
+            actual
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ssm_load_secure_string_parameter() {
+        let mock_client = rusoto_ssm::SsmClient::new_with(
+            MockRequestDispatcher::default().with_body(&MockResponseReader::read_response(
+                "testdata/awsssm",
+                "get-parameter-secure-string-response.json",
+            )),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let loader = AwsSsmLoader::with_client(mock_client);
+
+        assert_eq!(
+            Value::Secret(Secret::new("hunter2")),
+            loader.load_value("test.param").await.unwrap()
+        );
+        assert_eq!("hunter2", loader.load("test.param").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_ssm_load_parameter_throttled() {
+        let mock_client = rusoto_ssm::SsmClient::new_with(
+            MockRequestDispatcher::with_status(400)
+                .with_body(r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#),
+            MockCredentialsProvider,
+            Default::default(),
+        );
+
+        let loader = AwsSsmLoader::with_client(mock_client);
+        let err = loader.load("test.param").await.unwrap_err();
+
+        assert!(crate::Retryable::is(&err));
+        assert!(format!("{:#}", err).starts_with("Failed to fetch parameter: "));
+    }
+
     #[tokio::test]
     async fn test_ssm_load_parameter_not_found() {
         let mock_client = rusoto_ssm::SsmClient::new_with(
@@ -0,0 +1,283 @@
+//! Provides a loader which caches the values loaded by another loader
+//!
+//! # Examples
+//!
+//! ```
+//! use germinate::{CacheConfig, Seed};
+//! use std::time::Duration;
+//!
+//! let mut seed = Seed::new("%awsssm:/app/db/host%");
+//!
+//! // Keep SSM parameters for five minutes, and remember missing parameters for a minute
+//! seed.set_cache("awsssm", CacheConfig {
+//!     ttl: Duration::from_secs(300),
+//!     not_found_ttl: Some(Duration::from_secs(60)),
+//! });
+//! ```
+use crate::registry::lock;
+use crate::{Loader, NotFound, Value};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Configures how long a [`CachedLoader`](struct.CachedLoader.html) keeps values
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// How long a loaded value is kept before it's loaded again. Defaults to one minute
+    pub ttl: Duration,
+    /// How long a key which wasn't found is remembered for. When unset, keys which weren't found
+    /// are looked up again every time they're used
+    pub not_found_ttl: Option<Duration>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            not_found_ttl: None,
+        }
+    }
+}
+
+/// Wraps another loader, keeping the values it loads for the configured TTL. Errors other than
+/// `NotFound` are never cached, so a value which failed to load is tried again the next time it's
+/// used
+///
+/// When used with a [`LoaderRegistry`](struct.LoaderRegistry.html), the cache is shared by every
+/// template rendered using the registry
+///
+/// # Example
+/// ```
+/// use germinate::{CacheConfig, CachedLoader, Loader, Seed};
+///
+/// struct LanguageLoader {}
+///
+/// #[async_trait::async_trait]
+/// impl Loader for LanguageLoader {
+///     async fn load(&self, key: &str) -> anyhow::Result<String> {
+///         Ok(key.to_uppercase())
+///     }
+/// }
+///
+/// let mut seed = Seed::new("%language:rust%");
+/// seed.add_custom_loader(
+///     "language".into(),
+///     Box::new(CachedLoader::new(Box::new(LanguageLoader {}), CacheConfig::default())),
+/// );
+/// ```
+pub struct CachedLoader {
+    loader: Box<dyn Loader>,
+    config: CacheConfig,
+    values: Mutex<Entries>,
+}
+
+/// The cached results, keyed by the key they were loaded with
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    /// How many entries there can be before expired entries are swept out
+    sweep_at: usize,
+}
+
+/// A cached result, where a value which wasn't found is stored as the `NotFound` message
+struct Entry {
+    result: std::result::Result<Value, String>,
+    expires: Instant,
+}
+
+/// The fewest entries the cache is swept at
+const MIN_SWEEP_AT: usize = 64;
+
+impl CachedLoader {
+    /// Creates a new CachedLoader, caching the values loaded by the given loader
+    pub fn new(loader: Box<dyn Loader>, config: CacheConfig) -> Self {
+        Self {
+            loader,
+            config,
+            values: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Removes every cached value, so they're all loaded again the next time they're used
+    pub fn clear(&self) {
+        lock(&self.values).entries.clear();
+    }
+
+    /// Stores the result of loading a key, if it can be cached, and returns it. Expired entries
+    /// are swept out once the cache has doubled in size since it was last swept, so keys which
+    /// are never used again don't build up
+    fn store(&self, key: &str, result: Result<Value>) -> Result<Value> {
+        let (entry, ttl) = match (&result, self.config.not_found_ttl) {
+            (Ok(value), _) => (Ok(value.clone()), self.config.ttl),
+            (Err(error), Some(ttl)) if NotFound::is(error) => (Err(format!("{:#}", error)), ttl),
+            _ => return result,
+        };
+
+        let now = Instant::now();
+        let mut values = lock(&self.values);
+        if values.entries.len() >= values.sweep_at {
+            values.entries.retain(|_, entry| entry.expires > now);
+            values.sweep_at = (values.entries.len() * 2).max(MIN_SWEEP_AT);
+        }
+
+        values.entries.insert(
+            key.to_string(),
+            Entry {
+                result: entry,
+                expires: now + ttl,
+            },
+        );
+
+        result
+    }
+
+    /// Returns the cached result for the key if it hasn't expired
+    fn cached(&self, key: &str) -> Option<Result<Value>> {
+        let mut values = lock(&self.values);
+
+        match values.entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(match &entry.result {
+                Ok(value) => Ok(value.clone()),
+                Err(message) => Err(NotFound::new(message.clone()).into()),
+            }),
+            Some(_) => {
+                values.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader for CachedLoader {
+    /// Initialises the wrapped loader
+    async fn init(&mut self) -> Result<()> {
+        self.loader.init().await
+    }
+
+    /// Returns the cached value for the key as a string, or loads it using the wrapped loader's
+    /// `load_value`. Values are only cached once, however they're loaded, so the string is the
+    /// value as it's written into a template
+    async fn load(&self, key: &str) -> Result<String> {
+        Ok(self.load_value(key).await?.expose())
+    }
+
+    /// Returns the cached typed value for the key, or loads it using the wrapped loader
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        if let Some(result) = self.cached(key) {
+            return result;
+        }
+
+        let result = self.loader.load_value(key).await;
+        self.store(key, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the values it loads, returning NotFound for keys starting with `missing`
+    struct CountingLoader {
+        loads: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Loader for CountingLoader {
+        async fn load(&self, key: &str) -> Result<String> {
+            let count = self.loads.fetch_add(1, Ordering::SeqCst) + 1;
+
+            match key {
+                "error" => Err(anyhow::anyhow!("Connection refused")),
+                key if key.starts_with("missing") => {
+                    Err(NotFound::new(format!("Key not found '{}'", key)).into())
+                }
+                key => Ok(format!("{}-{}", key, count)),
+            }
+        }
+    }
+
+    fn counting_loader(config: CacheConfig) -> (CachedLoader, Arc<AtomicUsize>) {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let loader = CountingLoader {
+            loads: loads.clone(),
+        };
+
+        (CachedLoader::new(Box::new(loader), config), loads)
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader_caches_values() {
+        let (loader, loads) = counting_loader(CacheConfig::default());
+
+        assert_eq!("a-1", loader.load("a").await.unwrap());
+        assert_eq!("a-1", loader.load("a").await.unwrap());
+        assert_eq!("b-2", loader.load("b").await.unwrap());
+        assert_eq!(Value::from("a-1"), loader.load_value("a").await.unwrap());
+        assert_eq!(2, loads.load(Ordering::SeqCst));
+
+        loader.clear();
+        assert_eq!("a-3", loader.load("a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader_sweeps_expired_values() {
+        let (loader, loads) = counting_loader(CacheConfig {
+            ttl: Duration::from_millis(20),
+            not_found_ttl: None,
+        });
+
+        for i in 0..MIN_SWEEP_AT {
+            loader.load(&i.to_string()).await.unwrap();
+        }
+        assert_eq!(MIN_SWEEP_AT, lock(&loader.values).entries.len());
+
+        // Storing a value once the cache is full sweeps out the expired keys
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        loader.load("a").await.unwrap();
+        assert_eq!(1, lock(&loader.values).entries.len());
+        assert_eq!(MIN_SWEEP_AT + 1, loads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader_expires_values() {
+        let (loader, loads) = counting_loader(CacheConfig {
+            ttl: Duration::from_secs(0),
+            not_found_ttl: None,
+        });
+
+        assert_eq!("a-1", loader.load("a").await.unwrap());
+        assert_eq!("a-2", loader.load("a").await.unwrap());
+        assert_eq!(2, loads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_cached_loader_not_found() {
+        let (loader, loads) = counting_loader(CacheConfig::default());
+
+        // Without a not found TTL, missing keys and errors are looked up every time
+        assert!(loader.load("missing").await.is_err());
+        assert!(loader.load("missing").await.is_err());
+        assert!(loader.load("error").await.is_err());
+        assert!(loader.load("error").await.is_err());
+        assert_eq!(4, loads.load(Ordering::SeqCst));
+
+        let (loader, loads) = counting_loader(CacheConfig {
+            not_found_ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+
+        let err = loader.load("missing").await.unwrap_err();
+        assert!(NotFound::is(&err));
+        let err = loader.load("missing").await.unwrap_err();
+        assert!(NotFound::is(&err));
+        assert_eq!("Key not found 'missing'", err.to_string());
+        assert!(loader.load("error").await.is_err());
+        assert!(loader.load("error").await.is_err());
+        assert_eq!(3, loads.load(Ordering::SeqCst));
+    }
+}
@@ -7,7 +7,9 @@
 //! let output = tokio::runtime::Runtime::new().unwrap().block_on(seed.germinate()).unwrap();
 //! assert_eq!("Hi John", output);
 //! ```
+use crate::NotFound;
 use anyhow::Result;
+use std::env::VarError;
 
 pub(crate) const TEMPLATE_KEY: &str = "env";
 
@@ -25,7 +27,13 @@ impl crate::Loader for EnvironmentLoader {
     /// Load a value from the environment. The key is the name of the environment variable
     /// containing the value
     async fn load(&self, key: &str) -> Result<String> {
-        Ok(std::env::var(key)?)
+        match std::env::var(key) {
+            Ok(value) => Ok(value),
+            Err(VarError::NotPresent) => {
+                Err(NotFound::new(format!("Environment variable '{}' not set", key)).into())
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
@@ -45,4 +53,14 @@ mod test {
 
         assert_eq!(expected, actual);
     }
+
+    #[tokio::test]
+    async fn test_environment_loader_not_found() {
+        std::env::remove_var("ORC_TEST_MISSING_VAR");
+
+        let loader = EnvironmentLoader::new();
+        let err = loader.load("ORC_TEST_MISSING_VAR").await.unwrap_err();
+
+        assert!(err.is::<NotFound>());
+    }
 }
@@ -0,0 +1,24 @@
+//! Helpers for keys which pick values out of JSON documents returned by the loaders
+//!
+//! A key can be suffixed with `#` followed by a dot separated path to select a field from the
+//! document, for example `dynamic/instance-identity/document#accountId`. Numeric path segments
+//! index into arrays.
+
+/// Splits a key into the part used to load the document and the optional JSON path
+pub(crate) fn split_key(key: &str) -> (&str, Option<&str>) {
+    match key.find('#') {
+        Some(i) => (&key[..i], Some(&key[i + 1..])),
+        None => (key, None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!(("a/b", None), split_key("a/b"));
+        assert_eq!(("a/b", Some("c.d")), split_key("a/b#c.d"));
+    }
+}
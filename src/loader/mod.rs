@@ -1,15 +1,32 @@
+#[cfg(feature = "aws")]
+pub(crate) mod aws;
+
+#[cfg(feature = "aws")]
+pub(crate) mod awsec2;
+
 #[cfg(feature = "aws")]
 pub(crate) mod awsec2metadata;
 
 #[cfg(feature = "aws")]
 pub(crate) mod awsec2tag;
 
+#[cfg(feature = "aws")]
+pub(crate) mod awsecs;
+
+#[cfg(feature = "aws")]
+pub(crate) mod awss3;
+
 #[cfg(feature = "aws")]
 pub(crate) mod awsssm;
 
+pub(crate) mod cache;
 pub(crate) mod env;
+#[cfg(feature = "aws")]
+pub(crate) mod json;
 
+use crate::Value;
 use anyhow::Result;
+use std::fmt;
 
 /// A type implementing the Loader trait can be used to load a value from a store by it's key
 ///
@@ -19,35 +36,106 @@ use anyhow::Result;
 /// As the value could be loaded from an external network source, it must be done asynchronously to
 /// allow non-blocking value loading
 #[async_trait::async_trait]
-pub trait Loader {
+pub trait Loader: Send + Sync {
+    /// Init is called once before the first value is loaded. Anything which could fail, such as
+    /// creating clients or making network requests, should be done here rather than when the
+    /// loader is created so the failure can be reported against the source which caused it
+    async fn init(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Load takes a key and loads a value from the source using the key. As this could be over a
     /// network, we do this asynchronously
     async fn load(&self, key: &str) -> Result<String>;
+
+    /// Loads a typed value from the source using the key. This is used to write the value into a
+    /// template, and when the structure of the value matters, such as when looping over it or
+    /// selecting a field from it. By default the value returned by `load` is used as a string,
+    /// loaders which can return structured data should override this. Loaders for sensitive
+    /// values should return them as a [`Value::Secret`](enum.Value.html), so they're masked in
+    /// errors, reports and debug output
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        Ok(Value::String(self.load(key).await?))
+    }
+}
+
+/// The error returned when a loader is used before it has been initialised
+#[cfg(feature = "aws")]
+pub(crate) fn uninitialised() -> anyhow::Error {
+    anyhow::anyhow!("Loader used before it was initialised")
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub(crate) enum Source {
+    #[cfg(feature = "aws")]
+    AwsEc2,
+
     #[cfg(feature = "aws")]
     AwsEc2Tag,
 
     #[cfg(feature = "aws")]
     AwsEc2Metadata,
 
+    #[cfg(feature = "aws")]
+    AwsEcs,
+
+    #[cfg(feature = "aws")]
+    AwsS3,
+
     #[cfg(feature = "aws")]
     AwsSsm,
     Environment,
     Custom(String),
 }
 
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = match self {
+            #[cfg(feature = "aws")]
+            Self::AwsEc2 => awsec2::TEMPLATE_KEY,
+
+            #[cfg(feature = "aws")]
+            Self::AwsEc2Tag => awsec2tag::TEMPLATE_KEY,
+
+            #[cfg(feature = "aws")]
+            Self::AwsEc2Metadata => awsec2metadata::TEMPLATE_KEY,
+
+            #[cfg(feature = "aws")]
+            Self::AwsEcs => awsecs::TEMPLATE_KEY,
+
+            #[cfg(feature = "aws")]
+            Self::AwsS3 => awss3::TEMPLATE_KEY,
+
+            #[cfg(feature = "aws")]
+            Self::AwsSsm => awsssm::TEMPLATE_KEY,
+
+            Self::Environment => env::TEMPLATE_KEY,
+
+            Self::Custom(key) => key,
+        };
+
+        write!(f, "{}", key)
+    }
+}
+
 impl Source {
     pub(crate) fn from<T: AsRef<str>>(key: T) -> Self {
         match key.as_ref() {
+            #[cfg(feature = "aws")]
+            awsec2::TEMPLATE_KEY => Self::AwsEc2,
+
             #[cfg(feature = "aws")]
             awsec2tag::TEMPLATE_KEY => Self::AwsEc2Tag,
 
             #[cfg(feature = "aws")]
             awsec2metadata::TEMPLATE_KEY => Self::AwsEc2Metadata,
 
+            #[cfg(feature = "aws")]
+            awsecs::TEMPLATE_KEY => Self::AwsEcs,
+
+            #[cfg(feature = "aws")]
+            awss3::TEMPLATE_KEY => Self::AwsS3,
+
             #[cfg(feature = "aws")]
             awsssm::TEMPLATE_KEY => Self::AwsSsm,
 
@@ -3,11 +3,13 @@ extern crate clap;
 
 use anyhow::{Context, Result};
 use clap::{App, Arg};
-use germinate::Seed;
+use germinate::{ErrorMode, Format, OfflineCacheConfig, RateLimit, Report, RetryPolicy, Seed};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let matches = App::new("germinate")
+    let app = App::new("germinate")
         .about("Template files using values from various sources")
         .author(crate_authors!())
         .version(crate_version!())
@@ -16,6 +18,80 @@ async fn main() -> Result<()> {
                 .help("Template file to load and parse")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("fail-fast")
+                .help(
+                    "Stop at the first value that fails to load instead of reporting every failure",
+                )
+                .long("fail-fast"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Format to escape values for")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json", "yaml", "shell", "xml", "toml"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("detect-format")
+                .help("Detect the format to escape values for from the file extension")
+                .long("detect-format")
+                .conflicts_with("format"),
+        )
+        .arg(
+            Arg::with_name("structured")
+                .help("Parse the template as a JSON, YAML or TOML document and only replace placeholders inside it's values")
+                .long("structured"),
+        )
+        .arg(
+            Arg::with_name("offline-cache")
+                .help("Store loaded values in an encrypted cache file, and use them when their source fails")
+                .long("offline-cache")
+                .takes_value(true)
+                .requires("offline-cache-key"),
+        )
+        .arg(
+            Arg::with_name("offline-cache-key")
+                .help("32 byte key used to encrypt the offline cache, as hex or base64, e.g. from `openssl rand -hex 32`")
+                .long("offline-cache-key")
+                .env("GERMINATE_OFFLINE_CACHE_KEY")
+                .hide_env_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("offline-cache-max-staleness")
+                .help("How old a value from the offline cache can be, in seconds")
+                .long("offline-cache-max-staleness")
+                .takes_value(true)
+                .default_value("86400"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("How long to wait for each attempt to initialise a source or load a value, in seconds")
+                .long("timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-attempts")
+                .help("How many times to try initialising a source or loading a value when it fails with an error which can be retried")
+                .long("max-attempts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("deadline")
+                .help("How long rendering the whole template can take, in seconds")
+                .long("deadline")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .help("Limit how many values are loaded from a source each second, as SOURCE=PER_SECOND[:BURST], e.g. awsssm=10:20")
+                .long("rate-limit")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(
             Arg::with_name("output-file")
                 .help("Path to write the output to")
@@ -23,21 +99,257 @@ async fn main() -> Result<()> {
                 .long("output-file")
                 .takes_value(true)
                 .required(false),
+        );
+
+    #[cfg(feature = "aws")]
+    let app = app
+        .arg(
+            Arg::with_name("aws-endpoint-url")
+                .help("Override the endpoint used for AWS API requests")
+                .long("aws-endpoint-url")
+                .env("AWS_ENDPOINT_URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-region")
+                .help("The region to make AWS API requests in, detected from the environment or instance metadata if not given")
+                .long("aws-region")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-metadata-endpoint-url")
+                .help("Override the endpoint of the EC2 metadata service")
+                .long("aws-metadata-endpoint-url")
+                .env("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-profile")
+                .help("Load AWS credentials from the given profile")
+                .long("aws-profile")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-role-arn")
+                .help("Assume the given role when making AWS API requests")
+                .long("aws-role-arn")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-external-id")
+                .help("External ID to use when assuming a role")
+                .long("aws-external-id")
+                .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("aws-session-name")
+                .help("Session name to use when assuming a role")
+                .long("aws-session-name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-web-identity-token-file")
+                .help("Obtain AWS credentials using the given web identity token")
+                .long("aws-web-identity-token-file")
+                .env("AWS_WEB_IDENTITY_TOKEN_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aws-web-identity-role-arn")
+                .help("Role to assume with the web identity token")
+                .long("aws-web-identity-role-arn")
+                .env("AWS_ROLE_ARN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("awsec2tag-mode")
+                .help("Where to read EC2 instance tags from")
+                .long("awsec2tag-mode")
+                .takes_value(true)
+                .possible_values(&["auto", "metadata", "api"])
+                .default_value("auto"),
+        );
+
+    let matches = app.get_matches();
 
     // Safe to unwrap the input file as it's a required argument
-    let input = std::fs::read_to_string(matches.value_of("INPUT_FILE").unwrap())
-        .context("Failed to read input file")?;
+    let input_file = matches.value_of("INPUT_FILE").unwrap();
+    let structured = matches.is_present("structured");
+
+    // Structured documents have to be parsed as a whole, other templates are streamed from the
+    // input file so they never need to fit in memory
+    let input = match structured {
+        true => std::fs::read_to_string(input_file).context("Failed to read input file")?,
+        false => String::new(),
+    };
 
     let mut seed = Seed::new(&input);
-    let output = seed.germinate().await?;
+
+    // When detecting the format, the output file is the best guide to what's being rendered,
+    // falling back to the input file. Safe to unwrap as clap validates the possible values
+    let format = match matches.is_present("detect-format") {
+        true => match matches.value_of("output-file") {
+            Some(path) if path != "-" => Format::from_path(path),
+            _ => Format::from_path(input_file),
+        },
+        false => matches.value_of("format").unwrap().parse().unwrap(),
+    };
+    seed.set_format(format);
+
+    if !matches.is_present("fail-fast") {
+        seed.set_error_mode(ErrorMode::CollectAll);
+    }
+
+    // Parses a number of seconds, which can be fractional, e.g. 0.5
+    let seconds = |name| -> Result<Option<Duration>> {
+        match matches.value_of(name) {
+            Some(value) => value
+                .parse()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .map(Some)
+                .with_context(|| format!("Invalid {} '{}'", name, value)),
+            None => Ok(None),
+        }
+    };
+
+    let mut policy = RetryPolicy::default();
+    if let Some(timeout) = seconds("timeout")? {
+        policy.timeout = Some(timeout);
+    }
+    if let Some(attempts) = matches.value_of("max-attempts") {
+        policy.max_attempts = attempts
+            .parse()
+            .with_context(|| format!("Invalid max-attempts '{}'", attempts))?;
+    }
+    seed.set_retry_policy(policy);
+
+    if let Some(deadline) = seconds("deadline")? {
+        seed.set_deadline(deadline);
+    }
+
+    for value in matches.values_of("rate-limit").into_iter().flatten() {
+        let invalid = || format!("Invalid rate-limit '{}'", value);
+        let (source, limit) = parse_rate_limit(value).with_context(invalid)?;
+        seed.set_rate_limit(source, limit).with_context(invalid)?;
+    }
+
+    if let Some(path) = matches.value_of("offline-cache") {
+        // Safe to unwrap as the argument has a default value
+        let max_staleness = matches
+            .value_of("offline-cache-max-staleness")
+            .unwrap()
+            .parse()
+            .context("Invalid offline cache max staleness")?;
+
+        // Safe to unwrap the key as clap makes it required when the cache is used
+        seed.set_offline_cache(OfflineCacheConfig {
+            path: path.into(),
+            key: matches.value_of("offline-cache-key").unwrap().into(),
+            max_staleness: Duration::from_secs(max_staleness),
+        })
+        .context("Invalid offline-cache-key")?;
+    }
+
+    #[cfg(feature = "aws")]
+    {
+        let value = |name| matches.value_of(name).map(String::from);
+
+        seed.set_aws_config(germinate::AwsConfig {
+            endpoint_url: value("aws-endpoint-url"),
+            region: value("aws-region"),
+            metadata_endpoint_url: value("aws-metadata-endpoint-url"),
+            profile: value("aws-profile"),
+            role_arn: value("aws-role-arn"),
+            external_id: value("aws-external-id"),
+            session_name: value("aws-session-name"),
+            web_identity_token_file: value("aws-web-identity-token-file"),
+            web_identity_role_arn: value("aws-web-identity-role-arn"),
+        });
+
+        // Safe to unwrap as the argument has a default value and clap validates the possible
+        // values
+        seed.set_ec2_tag_mode(matches.value_of("awsec2tag-mode").unwrap().parse().unwrap());
+    }
 
     // If no output file is given, write the output to stdout
-    match matches.value_of("output-file").unwrap_or("-") {
-        "-" => print!("{}", output),
-        path => std::fs::write(path, output)?,
+    let result = match matches.value_of("output-file").unwrap_or("-") {
+        "-" => render(&mut seed, input_file, structured, tokio::io::stdout()).await,
+        path => {
+            // Render to a temporary file first so a failure doesn't leave a partly written file
+            let tmp_path = format!("{}.tmp", path);
+            let file = tokio::fs::File::create(&tmp_path)
+                .await
+                .context("Failed to create output file")?;
+
+            let result = render(&mut seed, input_file, structured, file).await;
+            match result {
+                Ok(()) => tokio::fs::rename(&tmp_path, path)
+                    .await
+                    .context("Failed to write output file"),
+                Err(err) => {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    Err(err)
+                }
+            }
+        }
     };
 
+    // Warn about any stale values used from the offline cache
+    if result.is_ok() && !seed.report().stale().is_empty() {
+        eprintln!("{}", seed.report());
+    }
+
+    if let Err(err) = result {
+        match err.downcast_ref::<Report>() {
+            // Print every failure, one per line, rather than the single error anyhow would show
+            Some(report) => {
+                eprintln!("{}", report);
+                std::process::exit(1);
+            }
+            None => return Err(err),
+        }
+    }
+
     Ok(())
 }
+
+/// Parses a rate limit given as SOURCE=PER_SECOND[:BURST], where the burst defaults to the rate
+/// rounded up
+fn parse_rate_limit(value: &str) -> Option<(&str, RateLimit)> {
+    let (source, limit) = value.split_once('=')?;
+    let (per_second, burst) = match limit.split_once(':') {
+        Some((per_second, burst)) => (per_second.parse::<f64>().ok()?, Some(burst.parse().ok()?)),
+        None => (limit.parse::<f64>().ok()?, None),
+    };
+
+    if source.is_empty() {
+        return None;
+    }
+
+    // The limit itself is checked when it's set
+    let burst = burst.unwrap_or((per_second.ceil() as u32).max(1));
+    Some((source, RateLimit { per_second, burst }))
+}
+
+/// Renders the input file to the output, streaming it unless it's rendered as a structured
+/// document
+async fn render<W: AsyncWrite + Unpin>(
+    seed: &mut Seed<'_>,
+    input_file: &str,
+    structured: bool,
+    mut output: W,
+) -> Result<()> {
+    if structured {
+        let document = seed.germinate_structured().await?;
+        output.write_all(document.as_bytes()).await?;
+        output.flush().await?;
+        return Ok(());
+    }
+
+    let input = tokio::fs::File::open(input_file)
+        .await
+        .context("Failed to read input file")?;
+
+    seed.germinate_stream(input, output).await
+}
@@ -0,0 +1,365 @@
+//! An encrypted cache of the last values loaded from each source, used to render templates when a
+//! source can't be reached
+use crate::loader::Source;
+use crate::registry::lock;
+use crate::{Secret, Value};
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// The length of the random nonce written at the start of the cache file
+const NONCE_LEN: usize = 12;
+
+/// The length of the key the cache file is encrypted with
+const KEY_LEN: usize = 32;
+
+/// Configures the offline cache, which stores the last value loaded for each placeholder in an
+/// encrypted file. When a value can't be loaded because it's source failed, the cached value is
+/// used instead as long as it's not older than `max_staleness`. Values which weren't found are
+/// never replaced by a cached value
+///
+/// # Example
+/// ```
+/// use germinate::{OfflineCacheConfig, Seed};
+/// use std::time::Duration;
+///
+/// let mut seed = Seed::new("%awsssm:/app/db/host%");
+///
+/// seed.set_offline_cache(OfflineCacheConfig {
+///     path: "/var/cache/germinate/values".into(),
+///     // A key generated with `openssl rand -hex 32`
+///     key: "8d2a6f1c4b9e0a7d3f5c1e8b2a4d6f0c9e7b5a3d1f8c6e4a2b0d9f7e5c3a1b8d".into(),
+///     max_staleness: Duration::from_secs(24 * 60 * 60),
+/// })
+/// .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct OfflineCacheConfig {
+    /// The path of the cache file. It's created when the first value is stored
+    pub path: PathBuf,
+    /// The 32 byte key the cache file is encrypted with, written as 64 hex characters or in
+    /// base64, such as the output of `openssl rand -hex 32`. It's used as the key as it is, rather
+    /// than derived from a passphrase, so it must be random. Keep it somewhere other than the
+    /// cache file's host disk. It's kept as a [`Secret`](struct.Secret.html) so it isn't shown
+    /// in debug output, and is zeroed once the cipher has been created from it
+    pub key: Secret,
+    /// How old a cached value can be and still be used
+    pub max_staleness: Duration,
+}
+
+/// A value as it's stored in the cache file
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    /// When the value was loaded, in seconds since the unix epoch
+    loaded_at: u64,
+    value: Value,
+}
+
+#[derive(Default)]
+struct State {
+    /// The cached values, or None until the cache file has been read
+    entries: Option<HashMap<String, Entry>>,
+    /// Whether values have been stored since the cache file was written
+    dirty: bool,
+}
+
+/// The offline cache used by a `LoaderRegistry`. The cache file is read the first time it's used,
+/// and written when a render finishes
+pub(crate) struct OfflineCache {
+    path: PathBuf,
+    max_staleness: Duration,
+    cipher: Aes256Gcm,
+    state: Mutex<State>,
+}
+
+impl OfflineCache {
+    /// Creates the offline cache, failing if the key isn't 32 bytes written in hex or base64
+    pub(crate) fn new(config: OfflineCacheConfig) -> Result<Self> {
+        // The key is dropped, and so zeroed, once the cipher has been created
+        let OfflineCacheConfig {
+            path,
+            key,
+            max_staleness,
+        } = config;
+        let key = parse_key(key.expose())?;
+
+        Ok(Self {
+            path,
+            max_staleness,
+            cipher: Aes256Gcm::new(key.as_slice().into()),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Stores a value which was loaded successfully
+    pub(crate) fn store(&self, source: &Source, kind: &str, key: &str, value: Value) {
+        let mut state = lock(&self.state);
+        let entries = self.entries(&mut state);
+        entries.insert(
+            cache_key(source, kind, key),
+            Entry {
+                loaded_at: now(),
+                value,
+            },
+        );
+        state.dirty = true;
+    }
+
+    /// Returns the cached value and when it was loaded, unless it's older than the max staleness
+    pub(crate) fn get(
+        &self,
+        source: &Source,
+        kind: &str,
+        key: &str,
+    ) -> Option<(Value, SystemTime)> {
+        let mut state = lock(&self.state);
+        let entry = self
+            .entries(&mut state)
+            .get(&cache_key(source, kind, key))?;
+
+        let loaded_at = UNIX_EPOCH + Duration::from_secs(entry.loaded_at);
+        match self.is_fresh(entry) {
+            true => Some((entry.value.clone(), loaded_at)),
+            false => None,
+        }
+    }
+
+    /// Writes the cache file if any values have been stored since it was last written. Values
+    /// older than the max staleness are dropped, as they'll never be used
+    pub(crate) fn save(&self) -> Result<()> {
+        let mut state = lock(&self.state);
+        if !state.dirty {
+            return Ok(());
+        }
+
+        let entries = self.entries(&mut state);
+        let fresh = entries
+            .iter()
+            .filter(|(_, entry)| self.is_fresh(entry))
+            .collect::<HashMap<_, _>>();
+        // The plaintext holds every cached value, so it's zeroed once it's been encrypted
+        let plaintext = Zeroizing::new(serde_json::to_vec(&fresh)?);
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut contents = nonce.to_vec();
+        contents.extend(
+            self.cipher
+                .encrypt((&nonce).into(), plaintext.as_slice())
+                .map_err(|_| anyhow!("Failed to encrypt offline cache"))?,
+        );
+
+        write_private(&self.path, &contents)
+            .with_context(|| format!("Failed to write offline cache '{}'", self.path.display()))?;
+
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn is_fresh(&self, entry: &Entry) -> bool {
+        Duration::from_secs(now().saturating_sub(entry.loaded_at)) <= self.max_staleness
+    }
+
+    /// Returns the cached values, reading the cache file if it hasn't been read yet. A cache file
+    /// which can't be read is logged and treated as empty, so it's replaced the next time it's
+    /// written
+    fn entries<'s>(&self, state: &'s mut State) -> &'s mut HashMap<String, Entry> {
+        state.entries.get_or_insert_with(|| match self.read() {
+            Ok(entries) => entries,
+            Err(error) => {
+                log::warn!("{:#}", error);
+                HashMap::new()
+            }
+        })
+    }
+
+    fn read(&self) -> Result<HashMap<String, Entry>> {
+        let path = &self.path;
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read offline cache '{}'", path.display()))
+            }
+        };
+
+        if contents.len() < NONCE_LEN {
+            return Err(anyhow!("Offline cache '{}' is corrupt", path.display()));
+        }
+
+        let (nonce, ciphertext) = contents.split_at(NONCE_LEN);
+        // Safe to unwrap as the nonce is exactly NONCE_LEN bytes
+        let nonce: &[u8; NONCE_LEN] = nonce.try_into().unwrap();
+        let plaintext = self.cipher.decrypt(nonce.into(), ciphertext).map_err(|_| {
+            anyhow!(
+                "Failed to decrypt offline cache '{}', it's corrupt or the key has changed",
+                path.display()
+            )
+        })?;
+        let plaintext = Zeroizing::new(plaintext);
+
+        serde_json::from_slice(&plaintext)
+            .with_context(|| format!("Offline cache '{}' is corrupt", path.display()))
+    }
+}
+
+/// Decodes the encryption key, which must be exactly 32 bytes written as hex or base64
+fn parse_key(key: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let key = key.trim();
+    let bytes = match key.len() == KEY_LEN * 2 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => (0..key.len())
+            .step_by(2)
+            // Safe to unwrap as every character is a hex digit
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16).unwrap())
+            .collect(),
+        false => base64::decode(key).unwrap_or_default(),
+    };
+    let bytes = Zeroizing::new(bytes);
+
+    if bytes.len() != KEY_LEN {
+        bail!(
+            "The offline cache key must be {} random bytes written as hex or base64, e.g. the output of `openssl rand -hex 32`",
+            KEY_LEN
+        );
+    }
+
+    Ok(bytes)
+}
+
+/// The key a value is stored under, which includes whether it was loaded as a string or a typed
+/// value, as a loader can return different values for each
+fn cache_key(source: &Source, kind: &str, key: &str) -> String {
+    format!("{}\0{}\0{}", kind, source, key)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Writes the file so it's only readable by the current user, replacing it in a single step so a
+/// failed write doesn't leave a partly written file
+fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn config(name: &str, key: &str) -> OfflineCacheConfig {
+        OfflineCacheConfig {
+            path: std::env::temp_dir().join(format!(
+                "germinate-offline-{}-{}",
+                name,
+                std::process::id()
+            )),
+            key: key.into(),
+            max_staleness: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_offline_cache_round_trip() {
+        let config = config("round-trip", KEY);
+        let source = Source::from("custom");
+
+        let cache = OfflineCache::new(config.clone()).unwrap();
+        cache.store(&source, "text", "a", Value::from("secret value"));
+        cache.store(&source, "value", "a", Value::Bytes(vec![0, 1, 2]));
+        cache.save().unwrap();
+
+        // The file is encrypted
+        let contents = std::fs::read(&config.path).unwrap();
+        assert!(!String::from_utf8_lossy(&contents).contains("secret value"));
+
+        let cache = OfflineCache::new(config.clone()).unwrap();
+        let (value, _) = cache.get(&source, "text", "a").unwrap();
+        assert_eq!(Value::from("secret value"), value);
+        let (value, _) = cache.get(&source, "value", "a").unwrap();
+        assert_eq!(Value::Bytes(vec![0, 1, 2]), value);
+        assert!(cache.get(&source, "text", "b").is_none());
+
+        // Values older than the max staleness aren't used
+        let cache = OfflineCache::new(OfflineCacheConfig {
+            max_staleness: Duration::from_secs(0),
+            ..config.clone()
+        })
+        .unwrap();
+        lock(&cache.state).entries = Some(
+            vec![(
+                cache_key(&source, "text", "a"),
+                Entry {
+                    loaded_at: now() - 10,
+                    value: Value::from("old"),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert!(cache.get(&source, "text", "a").is_none());
+
+        // A different key can't read the values
+        let cache = OfflineCache::new(OfflineCacheConfig {
+            key: base64::encode([1; KEY_LEN]).into(),
+            ..config.clone()
+        })
+        .unwrap();
+        assert!(cache.read().is_err());
+        assert!(cache.get(&source, "text", "a").is_none());
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_offline_cache_config_hides_key() {
+        let config = config("debug", KEY);
+
+        assert!(!format!("{:?}", config).contains(KEY));
+        assert_eq!(KEY, config.key.expose());
+    }
+
+    #[test]
+    fn test_offline_cache_key() {
+        assert_eq!((0..32).collect::<Vec<u8>>(), *parse_key(KEY).unwrap());
+        assert_eq!(
+            vec![7; KEY_LEN],
+            *parse_key(&base64::encode([7; KEY_LEN])).unwrap()
+        );
+        assert_eq!(
+            (0..32).collect::<Vec<u8>>(),
+            *parse_key(&format!("{}\n", KEY.to_uppercase())).unwrap()
+        );
+
+        // Passphrases and keys of the wrong length are rejected
+        assert!(parse_key("correct horse battery staple").is_err());
+        assert!(parse_key("secret").is_err());
+        assert!(parse_key(&KEY[2..]).is_err());
+        assert!(parse_key(&base64::encode([7; 16])).is_err());
+        assert!(OfflineCache::new(config("invalid-key", "secret")).is_err());
+    }
+}
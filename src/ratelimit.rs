@@ -0,0 +1,193 @@
+//! Token bucket rate limiting for the requests made by a source
+use crate::registry::lock;
+use crate::{Loader, Value};
+use anyhow::{bail, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits how often values are loaded from a source using a token bucket. The bucket starts full
+/// with `burst` tokens and refills at `per_second` tokens a second, and each value loaded, or
+/// retried, takes a token. When the bucket is empty, loading waits until there's a token rather
+/// than failing
+///
+/// The limit is shared by every template rendered using the same
+/// [`LoaderRegistry`](struct.LoaderRegistry.html). Only requests made to the source take a token,
+/// so values served from it's cache, when one is set with
+/// [`set_cache`](struct.Seed.html#method.set_cache), or from the offline cache don't. Waiting for
+/// a token counts towards the timeout of the source's [`RetryPolicy`](struct.RetryPolicy.html)
+///
+/// A template's placeholders are loaded one after another, not concurrently, so within a single
+/// render the limit only slows loading down once the burst is used up. It's most useful when
+/// several templates are rendered concurrently with a shared registry, or to spread out the
+/// requests made by a template with many placeholders
+///
+/// # Example
+/// ```
+/// use germinate::{RateLimit, Seed};
+///
+/// let mut seed = Seed::new("%awsssm:/app/db/host%");
+///
+/// // Make at most 5 SSM requests a second, after an initial burst of 10
+/// seed.set_rate_limit("awsssm", RateLimit {
+///     per_second: 5.0,
+///     burst: 10,
+/// })
+/// .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// How many tokens are added to the bucket each second. Must be greater than zero
+    pub per_second: f64,
+    /// How many tokens the bucket holds, which is how many values can be loaded at once before
+    /// the limit applies. Must be at least one
+    pub burst: u32,
+}
+
+/// A token bucket shared by everything loading values from a source
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// The tokens in the bucket, which is negative when tokens have been reserved by callers
+    /// waiting for the bucket to refill
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a full token bucket for the limit, failing if the limit would never let a value
+    /// load
+    pub(crate) fn new(limit: RateLimit) -> Result<Self> {
+        if !limit.per_second.is_finite() || limit.per_second <= 0.0 {
+            bail!(
+                "The rate limit must be a number of requests a second greater than zero, not {}",
+                limit.per_second
+            );
+        }
+        if limit.burst < 1 {
+            bail!("The rate limit burst must be at least 1");
+        }
+
+        Ok(Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(limit.burst),
+                refilled_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Takes a token from the bucket, waiting for one to be added if it's empty. Each caller
+    /// reserves it's token straight away, so concurrent callers are let through in the order they
+    /// arrived
+    pub(crate) async fn acquire(&self) {
+        let wait = {
+            let mut bucket = lock(&self.bucket);
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens =
+                (bucket.tokens + elapsed * self.limit.per_second).min(f64::from(self.limit.burst));
+            bucket.refilled_at = now;
+            bucket.tokens -= 1.0;
+
+            match bucket.tokens < 0.0 {
+                true => Duration::try_from_secs_f64(-bucket.tokens / self.limit.per_second)
+                    .unwrap_or(Duration::MAX),
+                false => Duration::ZERO,
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Wraps a loader, taking a token from the rate limiter before each value it loads. It's wrapped
+/// by any cache for the source, so only values which aren't cached take a token
+pub(crate) struct RateLimitedLoader {
+    loader: Box<dyn Loader>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedLoader {
+    pub(crate) fn new(loader: Box<dyn Loader>, limiter: Arc<RateLimiter>) -> Self {
+        Self { loader, limiter }
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader for RateLimitedLoader {
+    /// Initialises the wrapped loader
+    async fn init(&mut self) -> Result<()> {
+        self.loader.init().await
+    }
+
+    /// Waits for a token, then loads the value using the wrapped loader
+    async fn load(&self, key: &str) -> Result<String> {
+        self.limiter.acquire().await;
+        self.loader.load(key).await
+    }
+
+    /// Waits for a token, then loads the typed value using the wrapped loader
+    async fn load_value(&self, key: &str) -> Result<Value> {
+        self.limiter.acquire().await;
+        self.loader.load_value(key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = Arc::new(
+            RateLimiter::new(RateLimit {
+                per_second: 100.0,
+                burst: 2,
+            })
+            .unwrap(),
+        );
+        let start = Instant::now();
+
+        // The burst is let through straight away
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+
+        // Concurrent callers each wait for their own token
+        let tasks = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire().await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_rate_limit_must_allow_loads() {
+        for per_second in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limit = RateLimit {
+                per_second: *per_second,
+                burst: 1,
+            };
+            assert!(RateLimiter::new(limit).is_err());
+        }
+
+        let limit = RateLimit {
+            per_second: 1.0,
+            burst: 0,
+        };
+        assert!(RateLimiter::new(limit).is_err());
+    }
+}
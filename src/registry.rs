@@ -0,0 +1,387 @@
+//! A registry of loaders which can be shared between many `Seed`s
+use crate::loader::cache::{CacheConfig, CachedLoader};
+use crate::loader::{Loader, Source};
+use crate::offline::{OfflineCache, OfflineCacheConfig};
+use crate::ratelimit::{RateLimit, RateLimitedLoader, RateLimiter};
+use crate::retry::RetryPolicy;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(feature = "aws")]
+use crate::loader::aws::AwsConfig;
+#[cfg(feature = "aws")]
+use crate::loader::awsec2::AwsEc2Loader;
+#[cfg(feature = "aws")]
+use crate::loader::awsec2metadata::AwsEc2MetadataLoader;
+#[cfg(feature = "aws")]
+use crate::loader::awsec2tag::{AwsEc2TagLoader, Ec2TagMode};
+#[cfg(feature = "aws")]
+use crate::loader::awsecs::AwsEcsLoader;
+#[cfg(feature = "aws")]
+use crate::loader::awss3::AwsS3Loader;
+#[cfg(feature = "aws")]
+use crate::loader::awsssm::AwsSsmLoader;
+use crate::loader::env::EnvironmentLoader;
+
+/// Holds the loaders used to render templates. Loaders are created and initialised the first time
+/// a template uses their source, and then kept so every template rendered using the registry
+/// shares them, along with any clients or values they hold. Cloning a registry is cheap and the
+/// clone shares the same loaders, so it can be passed to many seeds using
+/// [`Seed::set_loader_registry`](struct.Seed.html#method.set_loader_registry)
+///
+/// # Example
+/// ```
+/// use germinate::{LoaderRegistry, Seed};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     std::env::set_var("NAME", "John");
+///
+///     let registry = LoaderRegistry::new();
+///
+///     for template in &["Hi %env:NAME%", "Bye %env:NAME%"] {
+///         let mut seed = Seed::new(template);
+///         seed.set_loader_registry(registry.clone());
+///         seed.germinate().await.unwrap();
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct LoaderRegistry {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    slots: Mutex<HashMap<Source, Arc<Slot>>>,
+    caches: Mutex<HashMap<Source, CacheConfig>>,
+    retry_policies: Mutex<RetryPolicies>,
+    rate_limiters: Mutex<HashMap<Source, Arc<RateLimiter>>>,
+    offline: Mutex<Option<Arc<OfflineCache>>>,
+    #[cfg(feature = "aws")]
+    config: Mutex<Config>,
+}
+
+/// The configuration used to create the AWS loaders
+#[cfg(feature = "aws")]
+struct Config {
+    aws_config: AwsConfig,
+    source_aws_configs: HashMap<Source, AwsConfig>,
+    ec2_tag_mode: Ec2TagMode,
+}
+
+/// The retry policy used by each source
+#[derive(Default)]
+struct RetryPolicies {
+    default: RetryPolicy,
+    sources: HashMap<Source, RetryPolicy>,
+}
+
+/// Holds a loader until it has been initialised, and then the initialised loader
+#[derive(Default)]
+struct Slot {
+    state: tokio::sync::Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    pending: Option<Box<dyn Loader>>,
+    ready: Option<Arc<dyn Loader>>,
+}
+
+impl LoaderRegistry {
+    /// Creates an empty registry
+    ///
+    /// When the `aws` feature is enabled, the AWS sources are configured using
+    /// `AwsConfig::from_env`
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                slots: Mutex::new(HashMap::new()),
+                caches: Mutex::new(HashMap::new()),
+                retry_policies: Mutex::new(RetryPolicies::default()),
+                rate_limiters: Mutex::new(HashMap::new()),
+                offline: Mutex::new(None),
+                #[cfg(feature = "aws")]
+                config: Mutex::new(Config {
+                    aws_config: AwsConfig::from_env(),
+                    source_aws_configs: HashMap::new(),
+                    ec2_tag_mode: Ec2TagMode::default(),
+                }),
+            }),
+        }
+    }
+
+    /// Sets the configuration used by the AWS sources. This only affects loaders which haven't
+    /// been created yet, so it should be called before rendering any templates
+    #[cfg(feature = "aws")]
+    pub fn set_aws_config(&self, config: AwsConfig) {
+        self.config().aws_config = config;
+    }
+
+    /// Sets the configuration used by a single AWS source, e.g. `awsssm`, in place of the
+    /// configuration set with `set_aws_config`. This only affects loaders which haven't been
+    /// created yet, so it should be called before rendering any templates
+    #[cfg(feature = "aws")]
+    pub fn set_source_aws_config(&self, source: &str, config: AwsConfig) {
+        self.config()
+            .source_aws_configs
+            .insert(Source::from(source), config);
+    }
+
+    /// Sets where the `awsec2tag` source reads the instance's tags from. This only affects the
+    /// loader if it hasn't been created yet
+    #[cfg(feature = "aws")]
+    pub fn set_ec2_tag_mode(&self, mode: Ec2TagMode) {
+        self.config().ec2_tag_mode = mode;
+    }
+
+    /// Adds a custom loader, which is used by every template rendered with the registry. Adding a
+    /// loader for a key which already has one replaces it
+    pub fn add_custom_loader(&self, key: String, loader: Box<dyn Loader>) {
+        let slot = Slot {
+            state: tokio::sync::Mutex::new(SlotState {
+                pending: Some(loader),
+                ready: None,
+            }),
+        };
+
+        self.slots().insert(Source::Custom(key), Arc::new(slot));
+    }
+
+    /// Caches the values loaded from a source, e.g. `awsssm`, using a
+    /// [`CachedLoader`](struct.CachedLoader.html). The cache is shared by every template rendered
+    /// using the registry. This only affects loaders which haven't been initialised yet, so it
+    /// should be called before rendering any templates
+    pub fn set_cache(&self, source: &str, config: CacheConfig) {
+        lock(&self.inner.caches).insert(Source::from(source), config);
+    }
+
+    /// Stores the values loaded by every template rendered using the registry in an encrypted
+    /// offline cache, so they can be used if their source fails when rendering later on. Fails if
+    /// the key isn't 32 bytes written as hex or base64
+    pub fn set_offline_cache(&self, config: OfflineCacheConfig) -> Result<()> {
+        *lock(&self.inner.offline) = Some(Arc::new(OfflineCache::new(config)?));
+        Ok(())
+    }
+
+    /// Returns the offline cache, if one has been set
+    pub(crate) fn offline_cache(&self) -> Option<Arc<OfflineCache>> {
+        lock(&self.inner.offline).clone()
+    }
+
+    /// Sets the policy used to time out and retry initialising loaders and loading values, for
+    /// every source without a policy of it's own
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        lock(&self.inner.retry_policies).default = policy;
+    }
+
+    /// Sets the policy used to time out and retry initialising the loader for a single source,
+    /// e.g. `awsssm`, and loading it's values, in place of the policy set with `set_retry_policy`
+    pub fn set_source_retry_policy(&self, source: &str, policy: RetryPolicy) {
+        lock(&self.inner.retry_policies)
+            .sources
+            .insert(Source::from(source), policy);
+    }
+
+    /// Returns the retry policy for the given source
+    pub(crate) fn retry_policy(&self, source: &Source) -> RetryPolicy {
+        let policies = lock(&self.inner.retry_policies);
+        *policies.sources.get(source).unwrap_or(&policies.default)
+    }
+
+    /// Limits how often values are loaded from a source, e.g. `awsssm`, by every template rendered
+    /// using the registry. Like `set_cache`, this only affects loaders which haven't been
+    /// initialised yet, so it should be called before rendering any templates. Fails if the
+    /// limit isn't greater than zero or the burst is less than one
+    pub fn set_rate_limit(&self, source: &str, limit: RateLimit) -> Result<()> {
+        let limiter = Arc::new(RateLimiter::new(limit)?);
+        lock(&self.inner.rate_limiters).insert(Source::from(source), limiter);
+        Ok(())
+    }
+
+    /// Returns the loader for the given source, creating and initialising it on first use. A
+    /// loader which fails to initialise is kept, and initialised again the next time it's used
+    pub(crate) async fn get(&self, source: &Source) -> Result<Arc<dyn Loader>> {
+        let slot = self.slots().entry(source.clone()).or_default().clone();
+
+        // Only one task initialises the loader, any others wait for it to finish
+        let mut state = slot.state.lock().await;
+        if let Some(loader) = &state.ready {
+            return Ok(loader.clone());
+        }
+
+        let mut loader = match state.pending.take() {
+            Some(loader) => loader,
+            None => self.create(source)?,
+        };
+
+        // Init takes the loader mutably, so it's retried here rather than using RetryPolicy::run
+        let policy = self.retry_policy(source);
+        let mut attempt = 1;
+        while let Err(error) = policy.attempt(loader.init()).await {
+            match policy.backoff(attempt, &error) {
+                Some(backoff) => tokio::time::sleep(backoff).await,
+                None => {
+                    state.pending = Some(loader);
+                    return Err(error);
+                }
+            }
+            attempt += 1;
+        }
+
+        // The rate limit goes under the cache, so values served from the cache don't take tokens
+        if let Some(limiter) = lock(&self.inner.rate_limiters).get(source).cloned() {
+            loader = Box::new(RateLimitedLoader::new(loader, limiter));
+        }
+
+        let cache = lock(&self.inner.caches).get(source).copied();
+        let loader: Arc<dyn Loader> = match cache {
+            Some(config) => Arc::new(CachedLoader::new(loader, config)),
+            None => Arc::from(loader),
+        };
+        state.ready = Some(loader.clone());
+        Ok(loader)
+    }
+
+    /// Creates a new loader for the given source. Creating a loader doesn't make any network
+    /// requests, that's left until the loader is initialised
+    fn create(&self, source: &Source) -> Result<Box<dyn Loader>> {
+        #[cfg(feature = "aws")]
+        let config = self.config();
+        #[cfg(feature = "aws")]
+        let aws_config = config
+            .source_aws_configs
+            .get(source)
+            .unwrap_or(&config.aws_config);
+
+        // Instantiate a new loader for the given key. If the key is for a custom source, we return
+        // an error as that should have been set using the add_custom_loader function before
+        // parsing
+        let loader: Box<dyn Loader> = match source {
+            #[cfg(feature = "aws")]
+            Source::AwsEc2 => Box::new(AwsEc2Loader::new(aws_config)),
+
+            #[cfg(feature = "aws")]
+            Source::AwsEc2Tag => Box::new(AwsEc2TagLoader::new(aws_config, config.ec2_tag_mode)),
+
+            #[cfg(feature = "aws")]
+            Source::AwsEc2Metadata => Box::new(AwsEc2MetadataLoader::new(aws_config)),
+
+            #[cfg(feature = "aws")]
+            Source::AwsEcs => Box::new(AwsEcsLoader::new()),
+
+            #[cfg(feature = "aws")]
+            Source::AwsS3 => Box::new(AwsS3Loader::new(aws_config)),
+
+            #[cfg(feature = "aws")]
+            Source::AwsSsm => Box::new(AwsSsmLoader::new(aws_config)),
+
+            Source::Environment => Box::new(EnvironmentLoader::new()),
+
+            Source::Custom(key) => return Err(
+                anyhow!(
+                    "Unsupported value source: {}. If you're using a custom source, make sure you added the loader before parsing",
+                    key
+                    )
+                ),
+        };
+
+        Ok(loader)
+    }
+
+    fn slots(&self) -> MutexGuard<'_, HashMap<Source, Arc<Slot>>> {
+        lock(&self.inner.slots)
+    }
+
+    #[cfg(feature = "aws")]
+    fn config(&self) -> MutexGuard<'_, Config> {
+        lock(&self.inner.config)
+    }
+}
+
+/// Locks a mutex. None of the crate's locks are held across a panic, so they can't be poisoned
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap()
+}
+
+impl Default for LoaderRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts how many times it's been initialised, and returns the key with the number of values
+    /// it's loaded
+    struct CountingLoader {
+        inits: Arc<AtomicUsize>,
+        loads: AtomicUsize,
+    }
+
+    impl CountingLoader {
+        fn new(inits: &Arc<AtomicUsize>) -> Self {
+            Self {
+                inits: inits.clone(),
+                loads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Loader for CountingLoader {
+        async fn init(&mut self) -> Result<()> {
+            self.inits.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn load(&self, key: &str) -> Result<String> {
+            Ok(format!(
+                "{}-{}",
+                key,
+                self.loads.fetch_add(1, Ordering::SeqCst) + 1
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loaders_are_shared() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let registry = LoaderRegistry::new();
+        registry.add_custom_loader("count".into(), Box::new(CountingLoader::new(&inits)));
+
+        let source = Source::from("count");
+        let clone = registry.clone();
+        let (a, b) = tokio::join!(registry.get(&source), clone.get(&source));
+
+        assert_eq!("key-1", a.unwrap().load("key").await.unwrap());
+        assert_eq!("key-2", b.unwrap().load("key").await.unwrap());
+        assert_eq!(1, inits.load(Ordering::SeqCst));
+
+        let err = registry.get(&Source::from("missing")).await.err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("Unsupported value source: missing"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_source() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let registry = LoaderRegistry::new();
+        registry.set_cache("count", CacheConfig::default());
+        registry.add_custom_loader("count".into(), Box::new(CountingLoader::new(&inits)));
+
+        let source = Source::from("count");
+        let a = registry.get(&source).await.unwrap();
+        let b = registry.clone().get(&source).await.unwrap();
+
+        assert_eq!("key-1", a.load("key").await.unwrap());
+        assert_eq!("key-1", b.load("key").await.unwrap());
+        assert_eq!("other-2", b.load("other").await.unwrap());
+        assert_eq!(1, inits.load(Ordering::SeqCst));
+    }
+}
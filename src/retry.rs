@@ -0,0 +1,190 @@
+//! Timeouts and retries for initialising loaders and loading values
+use crate::Retryable;
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+
+/// Controls how long initialising a loader or loading a value can take, and how failures are
+/// retried. Only failures which might not happen again are retried: timeouts, and errors marked
+/// as [`Retryable`](struct.Retryable.html) by the loader, such as AWS throttling errors or a
+/// metadata service which can't be reached. Retries wait for an exponentially increasing backoff,
+/// with a random jitter so many hosts retrying together don't stay in step
+///
+/// # Example
+/// ```
+/// use germinate::{RetryPolicy, Seed};
+/// use std::time::Duration;
+///
+/// let mut seed = Seed::new("%awsssm:/app/db/host%");
+///
+/// // Give SSM longer to respond, and retry it more times
+/// seed.set_source_retry_policy("awsssm", RetryPolicy {
+///     timeout: Some(Duration::from_secs(10)),
+///     max_attempts: 5,
+///     ..Default::default()
+/// });
+///
+/// // Give up if the whole template takes longer than a minute
+/// seed.set_deadline(Duration::from_secs(60));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How long a single attempt can take before it's cancelled and counted as a retryable
+    /// failure. Defaults to 30 seconds, or `None` to wait as long as it takes
+    pub timeout: Option<Duration>,
+    /// How many times to try before giving up, including the first attempt. Defaults to 3
+    pub max_attempts: u32,
+    /// The backoff before the first retry, which doubles for each retry after it. Defaults to 100
+    /// milliseconds
+    pub initial_backoff: Duration,
+    /// The longest backoff between retries. Defaults to 5 seconds
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(30)),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs the operation, retrying it until it succeeds, fails with an error which can't be
+    /// retried, or runs out of attempts
+    pub(crate) async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            match self.attempt(operation()).await {
+                Ok(value) => return Ok(value),
+                Err(error) => match self.backoff(attempt, &error) {
+                    Some(backoff) => tokio::time::sleep(backoff).await,
+                    None => return Err(error),
+                },
+            }
+
+            attempt += 1;
+        }
+    }
+
+    /// Runs a single attempt, failing with a retryable error if it takes longer than the timeout
+    pub(crate) async fn attempt<T, Fut>(&self, operation: Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return operation.await,
+        };
+
+        match tokio::time::timeout(timeout, operation).await {
+            Ok(result) => result,
+            Err(_) => Err(Retryable::new(format!("Timed out after {:?}", timeout)).into()),
+        }
+    }
+
+    /// Returns how long to wait before retrying after the given attempt failed, or None if it
+    /// shouldn't be retried. The backoff is a random duration up to the exponential backoff for
+    /// the attempt
+    pub(crate) fn backoff(&self, attempt: u32, error: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || !Retryable::is(error) {
+            return None;
+        }
+
+        let backoff = self
+            .initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+
+        Some(backoff.mul_f64(rand::random::<f64>()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Some(Duration::from_millis(50)),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_retryable_errors() {
+        let attempts = AtomicU32::new(0);
+        let result = policy()
+            .run(|| async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(Retryable::new("ThrottlingException").into()),
+                    _ => Ok("value"),
+                }
+            })
+            .await;
+
+        assert_eq!("value", result.unwrap());
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+
+        // Other errors fail straight away
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy()
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow::anyhow!("AccessDenied"))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy()
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok(())
+            })
+            .await;
+
+        let err = result.unwrap_err();
+        assert!(Retryable::is(&err));
+        assert_eq!("Timed out after 50ms", err.to_string());
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            ..policy()
+        };
+        let error = Retryable::new("Connection reset").into();
+
+        assert!(policy.backoff(1, &error).unwrap() <= Duration::from_millis(100));
+        assert!(policy.backoff(2, &error).unwrap() <= Duration::from_millis(200));
+        assert!(policy.backoff(3, &error).is_none());
+
+        let policy = RetryPolicy {
+            max_attempts: 40,
+            ..policy
+        };
+        assert!(policy.backoff(39, &error).unwrap() <= Duration::from_millis(300));
+    }
+}
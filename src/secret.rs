@@ -0,0 +1,81 @@
+//! A wrapper for sensitive values which keeps them out of errors, logs and debug output
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// What a secret is shown as in place of it's value
+pub(crate) const MASK: &str = "********";
+
+/// A sensitive value, such as a password or an SSM `SecureString` parameter. It's `Debug` and
+/// `Display` implementations show the value as `********`, so it can't end up in an error, a log
+/// message or a panic by accident, and the memory holding it is zeroed when it's dropped
+///
+/// Loaders mark a value as secret by returning a [`Value::Secret`](enum.Value.html) from
+/// [`Loader::load_value`](trait.Loader.html#method.load_value). Secrets are written into the
+/// rendered template as they are, but are masked in the values returned by
+/// [`Seed::parse_redacted`](struct.Seed.html#method.parse_redacted) and in any key listed in a
+/// [`Report`](struct.Report.html)
+///
+/// Only the memory the secret holds itself is zeroed. Copies made from it, such as the strings
+/// returned by [`Value::expose`](enum.Value.html#method.expose), `Loader::load` and
+/// [`Seed::parse`](struct.Seed.html#method.parse) or the rendered template, are plain strings
+/// which aren't zeroed when they're dropped
+///
+/// Serializing a secret writes it's value, which is only done by the encrypted offline cache
+///
+/// # Example
+/// ```
+/// use germinate::Secret;
+///
+/// let password = Secret::new("hunter2");
+///
+/// assert_eq!("********", password.to_string());
+/// assert_eq!("Secret(\"********\")", format!("{:?}", password));
+/// assert_eq!("hunter2", password.expose());
+/// ```
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Creates a new Secret holding the given value
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Returns the secret value. Copies of it aren't zeroed when they're dropped, so take care not
+    /// to keep them for longer than needed
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Secret").field(&MASK).finish()
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
@@ -2,35 +2,210 @@
 //! relevant loaders.
 //!
 //! Allows for custom loaders to be used via the `add_custom_loader` method
+use crate::document;
+use crate::error::{Failure, NotFound, Report, StaleValue};
+use crate::format::{Escaper, Format};
+use crate::loader::cache::CacheConfig;
 use crate::loader::{Loader, Source};
+use crate::offline::OfflineCacheConfig;
+use crate::ratelimit::RateLimit;
+use crate::registry::LoaderRegistry;
+use crate::retry::RetryPolicy;
+use crate::secret::Secret;
+use crate::template::{self, Condition, Node, Placeholder, Segment, Splitter, Subject, Variable};
+use crate::value::Value;
 use anyhow::{anyhow, Context, Result};
-use regex::Regex;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A boxed future, used to allow resolving nested placeholders recursively
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 #[cfg(feature = "aws")]
-use crate::loader::awsec2metadata::AwsEc2MetadataLoader;
-#[cfg(feature = "aws")]
-use crate::loader::awsec2tag::AwsEc2TagLoader;
+use crate::loader::aws::AwsConfig;
 #[cfg(feature = "aws")]
-use crate::loader::awsssm::AwsSsmLoader;
-use crate::loader::env::EnvironmentLoader;
+use crate::loader::awsec2tag::Ec2TagMode;
+
+/// Controls how a `Seed` handles failures while rendering a template
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ErrorMode {
+    /// Stop rendering at the first failure
+    #[default]
+    FailFast,
+    /// Skip the placeholders of any source whose loader fails to initialise and keep loading the
+    /// values from the other sources. Once every placeholder has been processed, the
+    /// initialisation failures are returned together in a [`Report`](struct.Report.html)
+    IsolateInitFailures,
+    /// Try to load every placeholder in the template, returning every initialisation and load
+    /// failure together in a [`Report`](struct.Report.html)
+    CollectAll,
+}
 
 /// A `Seed` is responsible for parsing the template string, loading the values, and optionally
 /// making the replacements via the germinate method
 pub struct Seed<'a> {
-    template: &'a str,
-    loaders: HashMap<Source, Box<dyn Loader>>,
+    template: Cow<'a, str>,
+    registry: LoaderRegistry,
+    error_mode: ErrorMode,
+    format: Format,
+    report: Report,
+    deadline: Option<Duration>,
 }
 
 impl<'a> Seed<'a> {
     /// Create a new `Seed` with the given template string
+    ///
+    /// When the `aws` feature is enabled, the AWS sources are configured using
+    /// `AwsConfig::from_env`
     pub fn new(template: &'a str) -> Self {
+        Self::with_template(Cow::Borrowed(template))
+    }
+
+    fn with_template(template: Cow<'a, str>) -> Self {
         Self {
             template,
-            loaders: HashMap::new(),
+            registry: LoaderRegistry::new(),
+            error_mode: ErrorMode::default(),
+            format: Format::default(),
+            report: Report::default(),
+            deadline: None,
         }
     }
 
+    /// Sets how failures are handled while rendering. Defaults to `ErrorMode::FailFast`
+    ///
+    /// # Example
+    /// ```
+    /// use germinate::{ErrorMode, Report, Seed};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut seed = Seed::new("%missing:one% %missing:two% %other:three%");
+    ///     seed.set_error_mode(ErrorMode::IsolateInitFailures);
+    ///
+    ///     let err = seed.germinate().await.unwrap_err();
+    ///     let report = err.downcast_ref::<Report>().unwrap();
+    ///
+    ///     // One failure is reported for each source which couldn't be initialised
+    ///     assert_eq!(2, report.failures().len());
+    /// }
+    /// ```
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        self.error_mode = mode;
+    }
+
+    /// Sets the format of the output, which controls how loaded values are escaped when they're
+    /// written into the template. Defaults to `Format::Text`, which writes values as they are
+    ///
+    /// # Example
+    /// ```
+    /// use germinate::{Format, Seed};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     std::env::set_var("GREETING", "say \"hi\"");
+    ///
+    ///     let mut seed = Seed::new(r#"{"greeting": "%env:GREETING%"}"#);
+    ///     seed.set_format(Format::Json);
+    ///
+    ///     assert_eq!(
+    ///         r#"{"greeting": "say \"hi\""}"#,
+    ///         seed.germinate().await.unwrap()
+    ///     );
+    /// }
+    /// ```
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Sets the configuration used by the AWS sources. This only affects loaders which haven't
+    /// been created yet, so it should be called before parsing. The configuration is stored in
+    /// the seed's loader registry, so it's shared with any other seeds using the same registry
+    #[cfg(feature = "aws")]
+    pub fn set_aws_config(&mut self, config: AwsConfig) {
+        self.registry.set_aws_config(config);
+    }
+
+    /// Sets the configuration used by a single AWS source, e.g. `awsssm`, in place of the
+    /// configuration set with `set_aws_config`. This only affects loaders which haven't been
+    /// created yet, so it should be called before parsing
+    #[cfg(feature = "aws")]
+    pub fn set_source_aws_config(&mut self, source: &str, config: AwsConfig) {
+        self.registry.set_source_aws_config(source, config);
+    }
+
+    /// Sets where the `awsec2tag` source reads the instance's tags from. Defaults to
+    /// `Ec2TagMode::Auto`, which uses the instance metadata tags endpoint when it's enabled and
+    /// falls back to the EC2 `DescribeInstances` API otherwise
+    #[cfg(feature = "aws")]
+    pub fn set_ec2_tag_mode(&mut self, mode: Ec2TagMode) {
+        self.registry.set_ec2_tag_mode(mode);
+    }
+
+    /// Caches the values loaded from a source, e.g. `awsssm`, for the configured TTL. The cache is
+    /// kept in the seed's loader registry, so it's shared by every render using the registry
+    pub fn set_cache(&mut self, source: &str, config: CacheConfig) {
+        self.registry.set_cache(source, config);
+    }
+
+    /// Sets the policy used to time out and retry initialising loaders and loading values, for
+    /// every source without a policy of it's own. The policy is stored in the seed's loader
+    /// registry
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.registry.set_retry_policy(policy);
+    }
+
+    /// Sets the policy used to time out and retry a single source, e.g. `awsssm`, in place of the
+    /// policy set with `set_retry_policy`
+    pub fn set_source_retry_policy(&mut self, source: &str, policy: RetryPolicy) {
+        self.registry.set_source_retry_policy(source, policy);
+    }
+
+    /// Limits how often values are loaded from a source, e.g. `awsssm`. See
+    /// [`RateLimit`](struct.RateLimit.html). Fails if the limit isn't greater than zero or the
+    /// burst is less than one
+    pub fn set_rate_limit(&mut self, source: &str, limit: RateLimit) -> Result<()> {
+        self.registry.set_rate_limit(source, limit)
+    }
+
+    /// Sets how long rendering the whole template can take, including any retries. Rendering
+    /// fails once the deadline has passed. There's no deadline by default
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Stores every loaded value in an encrypted offline cache. When a source fails to initialise
+    /// or a value fails to load, the value from the cache is used instead if it's not older than
+    /// the max staleness. Each stale value used is logged as a warning and listed in the render's
+    /// [`Report`](struct.Report.html). The cache is kept in the seed's loader registry. Fails if
+    /// the key isn't 32 bytes written as hex or base64
+    pub fn set_offline_cache(&mut self, config: OfflineCacheConfig) -> Result<()> {
+        self.registry.set_offline_cache(config)
+    }
+
+    /// Returns the report of the last successful render, which lists any stale values used from
+    /// the offline cache. When rendering fails, the report is returned as the error instead
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// Sets the registry the seed gets it's loaders from. Seeds sharing a registry share it's
+    /// loaders, so each loader is only created and initialised once, and custom loaders added to
+    /// the registry can be used by all of them. Each seed has it's own registry by default
+    pub fn set_loader_registry(&mut self, registry: LoaderRegistry) {
+        self.registry = registry;
+    }
+
+    /// Returns the registry the seed gets it's loaders from
+    pub fn loader_registry(&self) -> &LoaderRegistry {
+        &self.registry
+    }
+
     /// Adds a custom loader to allow users of the library to add their own value sources
     ///
     /// # Example
@@ -63,44 +238,12 @@ impl<'a> Seed<'a> {
     /// }
     /// ```
     pub fn add_custom_loader(&mut self, key: String, loader: Box<dyn Loader>) {
-        self.loaders.insert(Source::Custom(key), loader);
+        self.registry.add_custom_loader(key, loader);
     }
 
-    async fn get_loader(&mut self, source: &Source) -> Result<&dyn Loader> {
-        // If a loader with the given key exists, return it
-        if self.loaders.contains_key(source) {
-            // Unwrap should be safe here as we know the key exists
-            return Ok(self.loaders.get(source).unwrap().as_ref());
-        }
-
-        // Instantiate a new loader for the given key. If the key is for a custom source, we return
-        // an error as that should have been set using the add_custom_loader function before
-        // parsing
-        let loader: Box<dyn Loader> = match source {
-            #[cfg(feature = "aws")]
-            Source::AwsEc2Tag => Box::new(AwsEc2TagLoader::new().await?),
-
-            #[cfg(feature = "aws")]
-            Source::AwsEc2Metadata => Box::new(AwsEc2MetadataLoader::new()),
-
-            #[cfg(feature = "aws")]
-            Source::AwsSsm => Box::new(AwsSsmLoader::new().await?),
-
-            Source::Environment => Box::new(EnvironmentLoader::new()),
-
-            Source::Custom(key) => return Err(
-                anyhow!(
-                    "Unsupported value source: {}. If you're using a custom source, make sure you added the loader before parsing",
-                    key
-                    )
-                ),
-        };
-
-        // Store the new loader
-        self.loaders.insert(source.clone(), loader);
-
-        // Return a reference to the newly created loader
-        Ok(self.loaders.get(source).unwrap().as_ref())
+    /// Returns the loader for the given source, creating and initialising it on first use
+    async fn get_loader(&self, source: &Source) -> Result<Arc<dyn Loader>> {
+        self.registry.get(source).await
     }
 
     /// Parses the template string and generates a `HashMap` of key value replacements, loading the
@@ -108,6 +251,10 @@ impl<'a> Seed<'a> {
     /// without a related loader, it will return an error. It will also return an error if a value
     /// fails to load
     ///
+    /// The replacements are the values as they're written into the template, including the values
+    /// of any secrets. Use [`parse_redacted`](#method.parse_redacted) for replacements which can
+    /// be logged or shown
+    ///
     /// # Examples
     /// ```
     /// #[tokio::main]
@@ -121,35 +268,437 @@ impl<'a> Seed<'a> {
     /// }
     /// ```
     pub async fn parse(&mut self) -> Result<HashMap<String, String>> {
-        let mut replacements = HashMap::new();
+        Ok(self
+            .render()
+            .await?
+            .values
+            .into_iter()
+            .map(|(placeholder, value)| (placeholder, value.expose()))
+            .collect())
+    }
+
+    /// Parses the template in the same way as [`parse`](#method.parse), but values a loader marked
+    /// as [`Secret`](struct.Secret.html) are masked as `********`, so the replacements can be
+    /// logged or shown without leaking them, such as in a dry run
+    ///
+    /// # Examples
+    /// ```
+    /// use germinate::{Loader, Secret, Seed, Value};
+    ///
+    /// struct PasswordLoader {}
+    ///
+    /// #[async_trait::async_trait]
+    /// impl Loader for PasswordLoader {
+    ///     async fn load(&self, key: &str) -> anyhow::Result<String> {
+    ///         Ok(self.load_value(key).await?.expose())
+    ///     }
+    ///
+    ///     async fn load_value(&self, _key: &str) -> anyhow::Result<Value> {
+    ///         Ok(Value::Secret(Secret::new("hunter2")))
+    ///     }
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut seed = Seed::new("password: %password:db%");
+    ///     seed.add_custom_loader("password".into(), Box::new(PasswordLoader {}));
+    ///
+    ///     let replacements = seed.parse_redacted().await.unwrap();
+    ///     assert_eq!(replacements.get("%password:db%").unwrap(), &String::from("********"));
+    ///
+    ///     let replacements = seed.parse().await.unwrap();
+    ///     assert_eq!(replacements.get("%password:db%").unwrap(), &String::from("hunter2"));
+    /// }
+    /// ```
+    pub async fn parse_redacted(&mut self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .render()
+            .await?
+            .values
+            .into_iter()
+            .map(|(placeholder, value)| (placeholder, value.to_string()))
+            .collect())
+    }
+
+    /// Parses the template and renders it, loading the values of the placeholders as they're
+    /// reached. Placeholders in sections of the template which aren't rendered aren't loaded
+    async fn render(&mut self) -> Result<Render> {
+        within(self.deadline, self.render_template()).await
+    }
+
+    async fn render_template(&mut self) -> Result<Render> {
+        let nodes = template::parse(&self.template).context("Failed to parse template string")?;
+
+        let mut render = Render {
+            escaper: Escaper::new(self.format),
+            ..Default::default()
+        };
+        self.render_nodes(&nodes, &mut render).await?;
+        self.finish(&mut render)?;
+
+        Ok(render)
+    }
+
+    /// Finishes a render, writing the offline cache and returning the report as an error if there
+    /// were any failures
+    fn finish(&mut self, render: &mut Render) -> Result<()> {
+        if let Some(offline) = self.registry.offline_cache() {
+            // The template has still rendered, so failing to update the cache isn't an error
+            if let Err(error) = offline.save() {
+                log::warn!("{:#}", error);
+            }
+        }
+
+        let report = std::mem::take(&mut render.report);
+        if report.has_failures() {
+            self.report = Report::default();
+            return Err(report.into());
+        }
+
+        self.report = report;
+        Ok(())
+    }
+
+    /// Renders the nodes to the output
+    fn render_nodes<'s>(
+        &'s mut self,
+        nodes: &'s [Node],
+        render: &'s mut Render,
+    ) -> BoxFuture<'s, Result<()>> {
+        Box::pin(async move {
+            for (i, node) in nodes.iter().enumerate() {
+                // The text after a value decides whether it's the whole of a value in the output
+                let following = match nodes.get(i + 1) {
+                    Some(Node::Text(text)) => Some(text.as_str()),
+                    Some(_) => None,
+                    None => Some(""),
+                };
+
+                match node {
+                    Node::Text(text) => render.push(text),
+                    Node::Placeholder(placeholder) => match self.resolve(placeholder, render).await
+                    {
+                        Ok(value) => render
+                            .write(&value.expose(), placeholder.escape, following)
+                            .with_context(|| format!("Failed to render {}", placeholder.raw))?,
+                        Err(error) => self.record(error, render)?,
+                    },
+                    Node::Variable(variable) => {
+                        let value = render
+                            .variable(variable)
+                            .with_context(|| format!("Failed to render %{}%", variable))?
+                            .expose();
+                        render
+                            .write(&value, variable.escape, following)
+                            .with_context(|| format!("Failed to render %{}%", variable))?;
+                    }
+                    Node::Loop {
+                        index,
+                        item,
+                        placeholder,
+                        body,
+                    } => {
+                        let items: Value = match self.load_placeholder(placeholder, render).await {
+                            Ok(items) => items,
+                            Err(error) => {
+                                self.record(error, render)?;
+                                continue;
+                            }
+                        };
+
+                        for (i, value) in items.into_list().into_iter().enumerate() {
+                            let scope = render.variables.len();
+                            render.variables.push((item.clone(), value));
+                            if let Some(index) = index {
+                                render
+                                    .variables
+                                    .push((index.clone(), Value::Number(i as f64)));
+                            }
+
+                            self.render_nodes(body, render).await?;
+                            render.variables.truncate(scope);
+                        }
+                    }
+                    Node::Conditional {
+                        branches,
+                        otherwise,
+                    } => {
+                        let mut selected = Some(otherwise);
+
+                        for (condition, nodes) in branches {
+                            match self.evaluate(condition, render).await {
+                                Ok(true) => {
+                                    selected = Some(nodes);
+                                    break;
+                                }
+                                Ok(false) => {}
+                                // The failure has been recorded, but we can't know which branch
+                                // to render so skip the whole block
+                                Err(error) => {
+                                    self.record(error, render)?;
+                                    selected = None;
+                                    break;
+                                }
+                            }
+                        }
+
+                        if let Some(nodes) = selected {
+                            self.render_nodes(nodes, render).await?;
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Records a failure to load a value in the report, returning an error instead if the error
+    /// mode means rendering should stop
+    fn record(&self, error: PlaceholderError, render: &mut Render) -> Result<()> {
+        match error {
+            // The source has already failed to initialise and the failure has been recorded
+            PlaceholderError::Skipped => Ok(()),
+            PlaceholderError::Init(source, error) if self.error_mode != ErrorMode::FailFast => {
+                render.report.push(Failure::Init {
+                    source: source.to_string(),
+                    error,
+                });
+                render.failed_sources.insert(source);
+                Ok(())
+            }
+            PlaceholderError::Init(source, error) => {
+                Err(error.context(format!("Failed to initialise the '{}' source", source)))
+            }
+            PlaceholderError::Load {
+                placeholder,
+                source,
+                key,
+                error,
+            } if self.error_mode == ErrorMode::CollectAll => {
+                render.report.push(Failure::Load {
+                    placeholder,
+                    source: source.to_string(),
+                    key,
+                    error,
+                });
+                Ok(())
+            }
+            PlaceholderError::Load { error, .. } => Err(error.context("Failed to load value")),
+        }
+    }
+
+    /// Evaluates the condition of an `%#if%` or `%#elif%` tag. A value which doesn't exist is
+    /// treated as false rather than as a failure
+    async fn evaluate(
+        &mut self,
+        condition: &Condition,
+        render: &mut Render,
+    ) -> std::result::Result<bool, PlaceholderError> {
+        let placeholder = match &condition.subject {
+            Subject::Placeholder(placeholder) => placeholder,
+            // A path which doesn't exist in the variable is treated like a missing value
+            Subject::Variable(variable) => {
+                let value = render.variable(variable).ok().map(Value::expose);
+                return Ok(condition.evaluate(value.as_deref()));
+            }
+        };
+
+        let value = match self.load_placeholder(placeholder, render).await {
+            Ok(Text(value)) => Some(value.expose()),
+            Err(PlaceholderError::Load { error, .. }) if NotFound::is(&error) => None,
+            Err(error) => return Err(error),
+        };
+
+        Ok(condition.evaluate(value.as_deref()))
+    }
+
+    /// Loads the value for a placeholder, loading the values of any placeholders nested in it's
+    /// keys first. Values are stored so each placeholder is only loaded once, unless it uses a
+    /// loop variable and so can have a different value each time
+    fn resolve<'s>(
+        &'s mut self,
+        placeholder: &'s Placeholder,
+        render: &'s mut Render,
+    ) -> BoxFuture<'s, std::result::Result<Value, PlaceholderError>> {
+        Box::pin(async move {
+            let cache = !placeholder.uses_variables();
+            if let Some(value) = render.values.get(&placeholder.raw).filter(|_| cache) {
+                return Ok(value.clone());
+            }
+
+            let Text(value) = self.load_placeholder(placeholder, render).await?;
 
-        let pattern = Regex::new(r"(%([a-z0-9]+):([^%]+)%)").unwrap();
+            if cache {
+                render.values.insert(placeholder.raw.clone(), value.clone());
+            }
+            Ok(value)
+        })
+    }
 
-        for capture in pattern.captures_iter(self.template) {
-            // capture[1] will be the find string. If the map contains the key then we have already
-            // processed this replacement
-            if replacements.contains_key(&capture[1].to_string()) {
+    /// Loads the value for a placeholder, trying each of it's sources in order until one of them
+    /// finds a value. A source which can't be initialised is passed over like one without the
+    /// value, and the failure is only returned if none of the later sources have a value either
+    async fn load_placeholder<T: LoadAs>(
+        &mut self,
+        placeholder: &Placeholder,
+        render: &mut Render,
+    ) -> std::result::Result<T, PlaceholderError> {
+        let mut not_found = None;
+        let mut init_failure = None;
+        let mut skipped = false;
+
+        for alternative in &placeholder.alternatives {
+            let source = &alternative.source;
+            if render.failed_sources.contains(source) {
+                skipped = true;
                 continue;
             }
 
-            let source = Source::from(&capture[2]);
-            let loader = self
-                .get_loader(&source)
-                .await
-                .context("Failed to parse template string")?;
+            // Build the key, loading the values of any nested placeholders. The key is also built
+            // with any secrets masked, which is the key shown in errors and reports
+            let mut key = String::new();
+            let mut shown_key = String::new();
+            for segment in &alternative.key {
+                let value = match segment {
+                    Segment::Text(text) => Value::from(text.as_str()),
+                    Segment::Placeholder(nested) => self.resolve(nested, render).await?,
+                    Segment::Variable(variable) => match render.variable(variable) {
+                        Ok(value) => value.clone(),
+                        Err(error) => {
+                            return Err(PlaceholderError::Load {
+                                placeholder: placeholder.raw.clone(),
+                                source: source.clone(),
+                                key: shown_key,
+                                error: error.context(format!(
+                                    "Failed to build the key from %{}%",
+                                    variable
+                                )),
+                            })
+                        }
+                    },
+                };
 
-            // This is the key to use when loading the value
-            let key = &capture[3];
+                key.push_str(&value.expose());
+                shown_key.push_str(&value.to_string());
+            }
 
-            let value = loader
-                .load(&key.to_string())
-                .await
-                .context("Failed to load value")?;
+            // Once a source has failed to initialise, it's values come from the offline cache
+            let loader = match render.offline_sources.get(source) {
+                Some(error) => Err(anyhow!("{}", error)),
+                None => self.get_loader(source).await,
+            };
+
+            let loader = match loader {
+                Ok(loader) => loader,
+                Err(error) => {
+                    let message = format!("{:#}", error);
+                    match self.load_stale(source, &key, &shown_key, error, render) {
+                        Ok(value) => {
+                            render.offline_sources.insert(source.clone(), message);
+                            return Ok(value);
+                        }
+                        Err(error) => {
+                            init_failure.get_or_insert((source, error));
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let policy = self.registry.retry_policy(source);
+            match policy.run(|| T::load(&*loader, &key)).await {
+                Ok(value) => {
+                    if let Some(offline) = self.registry.offline_cache() {
+                        offline.store(source, T::KIND, &key, value.to_value());
+                    }
+                    return Ok(value);
+                }
+                Err(error) if NotFound::is(&error) => not_found = Some((source, shown_key, error)),
+                Err(error) => {
+                    return self
+                        .load_stale(source, &key, &shown_key, error, render)
+                        .map_err(|error| PlaceholderError::Load {
+                            placeholder: placeholder.raw.clone(),
+                            source: source.clone(),
+                            key: shown_key,
+                            error,
+                        })
+                }
+            }
+        }
+
+        // Without every source, there's no telling whether the value is really missing
+        if let Some((source, error)) = init_failure {
+            return Err(PlaceholderError::Init(source.clone(), error));
+        }
 
-            replacements.insert(capture[1].to_string(), value);
+        // The failure of a skipped source has already been recorded
+        if skipped {
+            return Err(PlaceholderError::Skipped);
         }
 
-        Ok(replacements)
+        if placeholder.optional {
+            return Ok(T::missing());
+        }
+
+        // Safe to unwrap as a placeholder always has at least one alternative, and we only get
+        // here if every one of them returned a NotFound error
+        let (source, key, error) = not_found.unwrap();
+
+        let error = match placeholder.alternatives.len() {
+            1 => error,
+            _ => NotFound::new(format!(
+                "No value found in any of {}",
+                placeholder
+                    .alternatives
+                    .iter()
+                    .map(|alternative| alternative.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+            .into(),
+        };
+
+        Err(PlaceholderError::Load {
+            placeholder: placeholder.raw.clone(),
+            source: source.clone(),
+            key,
+            error,
+        })
+    }
+
+    /// Returns the value from the offline cache after a source failed, recording that a stale value
+    /// was used under the shown key. The error is returned if there's no offline cache or it
+    /// doesn't have a fresh enough value
+    fn load_stale<T: LoadAs>(
+        &self,
+        source: &Source,
+        key: &str,
+        shown_key: &str,
+        error: anyhow::Error,
+        render: &mut Render,
+    ) -> Result<T> {
+        let cached = self
+            .registry
+            .offline_cache()
+            .and_then(|offline| offline.get(source, T::KIND, key));
+
+        let (value, loaded_at) = match cached {
+            Some(cached) => cached,
+            None => return Err(error),
+        };
+
+        let stale = StaleValue {
+            source: source.to_string(),
+            key: shown_key.to_string(),
+            loaded_at,
+            error,
+        };
+        log::warn!("{}", stale);
+        render.report.push_stale(stale);
+
+        Ok(T::from_value(value))
     }
 
     /// The germinate is a wrapper around the parse function which follows up by actually making
@@ -169,21 +718,402 @@ impl<'a> Seed<'a> {
     ///
     /// ```
     pub async fn germinate(&mut self) -> Result<String> {
-        let mut output = self.template.to_string();
+        Ok(self.render().await?.output)
+    }
+
+    /// Renders the template and writes the output to the writer. Nothing is written if the
+    /// template fails to render
+    ///
+    /// # Examples
+    /// ```
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     std::env::set_var("NAME", "John");
+    ///
+    ///     let mut seed = germinate::Seed::new("Hi %env:NAME%!");
+    ///     let mut output = Vec::new();
+    ///     seed.render_to(&mut output).await.unwrap();
+    ///
+    ///     assert_eq!(b"Hi John!", output.as_slice());
+    /// }
+    /// ```
+    pub async fn render_to<W: std::io::Write + Send>(&mut self, writer: &mut W) -> Result<()> {
+        let output = self.render().await?.output;
+        writer
+            .write_all(output.as_bytes())
+            .context("Failed to write output")?;
+        writer.flush().context("Failed to write output")
+    }
+
+    /// Renders a template read from the reader, writing the output to the writer as it goes,
+    /// instead of rendering the template the `Seed` was created with. Only a line at a time is
+    /// held in memory, or a whole block for `%#if%` and `%#for%` blocks, so this can be used for
+    /// templates which are too large to load into memory. Lines longer than 16 KiB, which is the
+    /// longest a placeholder can be, are split rather than held whole
+    ///
+    /// Output is written as each part of the template is rendered, so when a value fails to load
+    /// the output may have been partly written. Nothing is written from the part of the template
+    /// where the first failure happened onwards, so with `ErrorMode::CollectAll` the rest of the
+    /// template is still rendered to report every failure, but values which failed to load are
+    /// never written as empty strings
+    ///
+    /// # Examples
+    /// ```
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     std::env::set_var("NAME", "John");
+    ///
+    ///     let mut seed = germinate::Seed::new("");
+    ///     let mut output = Vec::new();
+    ///     seed.germinate_stream(&b"Hi %env:NAME%!\nBye %env:NAME%!\n"[..], &mut output)
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     assert_eq!(b"Hi John!\nBye John!\n", output.as_slice());
+    /// }
+    /// ```
+    pub async fn germinate_stream<R, W>(&mut self, reader: R, writer: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        within(self.deadline, self.stream(reader, writer)).await
+    }
+
+    async fn stream<R, W>(&mut self, mut reader: R, mut writer: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut render = Render {
+            escaper: Escaper::new(self.format),
+            ..Default::default()
+        };
+        let mut buffer = Vec::new();
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+        let mut splitter = Splitter::default();
+
+        loop {
+            let read = reader
+                .read(&mut chunk)
+                .await
+                .context("Failed to read template")?;
+            buffer.extend_from_slice(&chunk[..read]);
+
+            // A character can be split between reads, so only the valid start of the buffer is
+            // used until the rest of it has been read
+            let text = match std::str::from_utf8(&buffer) {
+                Ok(text) => text,
+                Err(e) if e.error_len().is_none() && read > 0 => {
+                    // Safe as the buffer is valid up to this point
+                    std::str::from_utf8(&buffer[..e.valid_up_to()]).unwrap()
+                }
+                Err(e) => return Err(e).context("Template is not valid UTF-8"),
+            };
+
+            // Once everything has been read, the rest of the template is rendered
+            let len = match read {
+                0 => text.len(),
+                _ => splitter.complete_len(text),
+            };
+
+            if len > 0 {
+                let nodes =
+                    template::parse(&text[..len]).context("Failed to parse template string")?;
+                self.render_nodes(&nodes, &mut render).await?;
+
+                // Once anything has failed, the output can't be complete
+                if !render.report.has_failures() {
+                    writer
+                        .write_all(render.output.as_bytes())
+                        .await
+                        .context("Failed to write output")?;
+                }
+                render.output.clear();
+                buffer.drain(..len);
+                splitter.split(len);
+            }
+
+            if read == 0 {
+                break;
+            }
+        }
+
+        writer.flush().await.context("Failed to write output")?;
 
-        for (k, v) in self.parse().await? {
-            output = output.replace(&k, &v);
+        self.finish(&mut render)
+    }
+
+    /// Renders the template as a structured document rather than as text. The template is parsed
+    /// using the format set with `set_format`, which must be `Format::Json`, `Format::Yaml` or
+    /// `Format::Toml`, and placeholders are resolved inside it's string values before it's written
+    /// back out. A string which is entirely a single placeholder takes the type of the loaded
+    /// value, so a number, boolean or list stays one, and values never need to be escaped
+    ///
+    /// The order of keys is kept, but comments and formatting are not
+    ///
+    /// # Examples
+    /// ```
+    /// use germinate::{Format, Seed};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     std::env::set_var("MOTD", "Welcome: it's \"live\"");
+    ///
+    ///     let mut seed = Seed::new("motd: '%env:MOTD%'\nport: 8080\n");
+    ///     seed.set_format(Format::Yaml);
+    ///     let output = seed.germinate_structured().await.unwrap();
+    ///
+    ///     assert_eq!("---\nmotd: \"Welcome: it's \\\"live\\\"\"\nport: 8080\n", output);
+    /// }
+    /// ```
+    pub async fn germinate_structured(&mut self) -> Result<String> {
+        within(self.deadline, self.structured()).await
+    }
+
+    async fn structured(&mut self) -> Result<String> {
+        let mut document = document::parse(&self.template, self.format)?;
+
+        // Values are written into the document as they are, it's serialiser handles quoting
+        let mut render = Render::default();
+
+        for scalar in document::strings(&mut document) {
+            let text = scalar.as_str().unwrap_or_default().to_string();
+            let nodes = template::parse(&text)
+                .with_context(|| format!("Failed to parse template string '{}'", text))?;
+
+            *scalar = match nodes.as_slice() {
+                [Node::Placeholder(placeholder)] => {
+                    match self
+                        .load_placeholder::<Value>(placeholder, &mut render)
+                        .await
+                    {
+                        Ok(value) => value.to_json(),
+                        Err(error) => {
+                            self.record(error, &mut render)?;
+                            continue;
+                        }
+                    }
+                }
+                nodes => {
+                    self.render_nodes(nodes, &mut render).await?;
+                    serde_json::Value::String(std::mem::take(&mut render.output))
+                }
+            };
         }
 
-        Ok(output)
+        self.finish(&mut render)?;
+
+        document::write(&document, self.format)
+    }
+}
+
+impl Seed<'static> {
+    /// Create a new `Seed` which owns it's template string. Unlike a `Seed` created with `new`, it
+    /// doesn't borrow anything, so it can be stored in other types or moved to another task
+    ///
+    /// # Examples
+    /// ```
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     std::env::set_var("NAME", "John");
+    ///
+    ///     let mut seed = germinate::Seed::from_string(format!("Hi %env:{}%!", "NAME"));
+    ///     let output = tokio::spawn(async move { seed.germinate().await })
+    ///         .await
+    ///         .unwrap()
+    ///         .unwrap();
+    ///
+    ///     assert_eq!("Hi John!", output);
+    /// }
+    /// ```
+    pub fn from_string(template: String) -> Self {
+        Self::with_template(Cow::Owned(template))
     }
 }
 
+impl From<String> for Seed<'static> {
+    fn from(template: String) -> Self {
+        Self::from_string(template)
+    }
+}
+
+impl<'a> From<&'a str> for Seed<'a> {
+    fn from(template: &'a str) -> Self {
+        Self::new(template)
+    }
+}
+
+/// Runs a render, failing if it hasn't finished by the deadline
+async fn within<T>(
+    deadline: Option<Duration>,
+    render: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return render.await,
+    };
+
+    match tokio::time::timeout(deadline, render).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(
+            "Rendering the template didn't finish within the {:?} deadline",
+            deadline
+        )),
+    }
+}
+
+/// How much of the template is read at a time when streaming
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The output and values built up while rendering a template
+#[derive(Default)]
+struct Render {
+    output: String,
+    values: HashMap<String, Value>,
+    report: Report,
+    failed_sources: HashSet<Source>,
+    /// The sources which failed to initialise and are being loaded from the offline cache, with
+    /// the error they failed with
+    offline_sources: HashMap<Source, String>,
+    /// The loop variables in scope, innermost last
+    variables: Vec<(String, Value)>,
+    /// Where the end of the output is, which values are escaped for when they're written to it
+    escaper: Escaper,
+}
+
+impl Render {
+    /// Writes text from the template to the output
+    fn push(&mut self, text: &str) {
+        self.escaper.push(text);
+        self.output.push_str(text);
+    }
+
+    /// Writes a loaded value to the output, escaping it for the format unless escaping is turned
+    /// off for the placeholder. `following` is the text after the value in the template, if it's
+    /// followed by text
+    fn write(&mut self, value: &str, escape: bool, following: Option<&str>) -> Result<()> {
+        let value = match escape {
+            true => Cow::Owned(self.escaper.escape(value, following)?),
+            false => Cow::Borrowed(value),
+        };
+
+        self.push(&value);
+        Ok(())
+    }
+
+    /// Returns the value of the loop variable, or the field selected by it's path. Fails with
+    /// NotFound if the path doesn't exist in the value
+    fn variable(&self, variable: &Variable) -> Result<&Value> {
+        let value = self
+            .variables
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == variable.name)
+            .map(|(_, value)| value)
+            // Variables are checked against the loops which define them when parsing
+            .expect("Loop variable should be defined");
+
+        value.select(variable.path.as_deref().unwrap_or_default())
+    }
+}
+
+/// The types the value of a placeholder can be loaded as
+#[async_trait::async_trait]
+trait LoadAs: Sized + Send {
+    /// Identifies how the value was loaded in the offline cache
+    const KIND: &'static str;
+
+    /// Loads the value for the key using the loader
+    async fn load(loader: &dyn Loader, key: &str) -> Result<Self>;
+
+    /// The value of an optional placeholder which wasn't found
+    fn missing() -> Self;
+
+    /// Converts the value so it can be stored in the offline cache
+    fn to_value(&self) -> Value;
+
+    /// Converts a value read from the offline cache
+    fn from_value(value: Value) -> Self;
+}
+
+/// A value loaded to be written into the template as text, which is either a string or a secret
+struct Text(Value);
+
+#[async_trait::async_trait]
+impl LoadAs for Text {
+    const KIND: &'static str = "text";
+
+    async fn load(loader: &dyn Loader, key: &str) -> Result<Self> {
+        // The value is written as text, which is kept secret if any part of it is
+        match loader.load_value(key).await? {
+            Value::Secret(secret) => Ok(Text(Value::Secret(secret))),
+            Value::Bytes(_) => Err(anyhow!("Value is not valid UTF-8")),
+            value if value.has_secret() => Ok(Text(Value::Secret(Secret::new(value.expose())))),
+            value => Ok(Text(Value::String(value.expose()))),
+        }
+    }
+
+    fn missing() -> Self {
+        Text(Value::String(String::new()))
+    }
+
+    fn to_value(&self) -> Value {
+        self.0.clone()
+    }
+
+    fn from_value(value: Value) -> Self {
+        Text(value)
+    }
+}
+
+#[async_trait::async_trait]
+impl LoadAs for Value {
+    const KIND: &'static str = "value";
+
+    async fn load(loader: &dyn Loader, key: &str) -> Result<Self> {
+        loader.load_value(key).await
+    }
+
+    fn missing() -> Self {
+        Value::String(String::new())
+    }
+
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+
+    fn from_value(value: Value) -> Self {
+        value
+    }
+}
+
+/// The ways loading the value for a placeholder can fail
+enum PlaceholderError {
+    /// One of the sources has already failed to initialise
+    Skipped,
+    Init(Source, anyhow::Error),
+    Load {
+        placeholder: String,
+        source: Source,
+        key: String,
+        error: anyhow::Error,
+    },
+}
+
 #[cfg(test)]
 mod test {
-    use super::Seed;
-    use crate::Loader;
+    use super::{ErrorMode, Format, Seed, STREAM_CHUNK_SIZE};
+    use crate::loader::Source;
+    use crate::template::MAX_PLACEHOLDER_LEN;
+    use crate::{
+        CacheConfig, Failure, Loader, LoaderRegistry, NotFound, OfflineCacheConfig, RateLimit,
+        Report, RetryPolicy, Retryable, Secret, Value,
+    };
     use anyhow::Result;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     struct TestLoader {
         value: String,
@@ -212,6 +1142,762 @@ mod test {
         assert_eq!(String::from("Test Test Test"), output);
     }
 
+    struct FailingLoader {}
+
+    #[async_trait::async_trait]
+    impl Loader for FailingLoader {
+        async fn init(&mut self) -> Result<()> {
+            Err(anyhow::anyhow!("No credentials"))
+        }
+
+        async fn load(&self, _: &str) -> Result<String> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_init_failure_names_source() {
+        let mut seed = Seed::new("Test %failing:test% Test");
+        seed.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+
+        let err = seed.germinate().await.unwrap_err();
+
+        assert_eq!(
+            "Failed to initialise the 'failing' source: No credentials",
+            format!("{:#}", err)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_germinate_isolates_init_failures() {
+        let mut seed = Seed::new("%failing:a% %custom:b% %failing:c% %missing:d%");
+        seed.set_error_mode(ErrorMode::IsolateInitFailures);
+        seed.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+        seed.add_custom_loader(
+            "custom".into(),
+            Box::new(TestLoader::with_value("Test".into())),
+        );
+
+        let err = seed.parse().await.unwrap_err();
+        let report = err.downcast_ref::<Report>().unwrap();
+        let sources = report
+            .failures()
+            .iter()
+            .map(|f| match f {
+                Failure::Init { source, .. } => source.as_str(),
+                other => panic!("Unexpected failure: {}", other),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["failing", "missing"], sources);
+    }
+
+    #[tokio::test]
+    async fn test_germinate_collects_all_failures() {
+        std::env::remove_var("GERMINATE_TEST_MISSING_ONE");
+        std::env::remove_var("GERMINATE_TEST_MISSING_TWO");
+
+        let mut seed = Seed::new(
+            "%env:GERMINATE_TEST_MISSING_ONE% %failing:a% %env:GERMINATE_TEST_MISSING_TWO%",
+        );
+        seed.set_error_mode(ErrorMode::CollectAll);
+        seed.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+
+        let err = seed.germinate().await.unwrap_err();
+        let report = err.downcast_ref::<Report>().unwrap();
+
+        assert_eq!(3, report.failures().len());
+        match &report.failures()[0] {
+            Failure::Load {
+                placeholder,
+                source,
+                key,
+                ..
+            } => {
+                assert_eq!("%env:GERMINATE_TEST_MISSING_ONE%", placeholder);
+                assert_eq!("env", source);
+                assert_eq!("GERMINATE_TEST_MISSING_ONE", key);
+            }
+            other => panic!("Unexpected failure: {}", other),
+        }
+        assert!(matches!(report.failures()[1], Failure::Init { .. }));
+        assert!(matches!(report.failures()[2], Failure::Load { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_optional_placeholders() {
+        std::env::set_var("GERMINATE_TEST_PRESENT", "present");
+        std::env::remove_var("GERMINATE_TEST_ABSENT");
+
+        let mut seed = Seed::new("[%env?:GERMINATE_TEST_PRESENT%][%env?:GERMINATE_TEST_ABSENT%]");
+        assert_eq!("[present][]", seed.germinate().await.unwrap());
+
+        // Required placeholders still fail when the value is missing
+        let mut seed = Seed::new("[%env:GERMINATE_TEST_ABSENT%]");
+        assert!(seed.germinate().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_germinate_fallback_chains() {
+        std::env::set_var("GERMINATE_TEST_OVERRIDE", "env value");
+        std::env::remove_var("GERMINATE_TEST_NOT_OVERRIDDEN");
+
+        let mut seed = Seed::new(
+            "%env,custom:GERMINATE_TEST_OVERRIDE% %env:GERMINATE_TEST_NOT_OVERRIDDEN || custom:/app/value%",
+        );
+        seed.add_custom_loader(
+            "custom".into(),
+            Box::new(TestLoader::with_value("custom value".into())),
+        );
+
+        assert_eq!("env value custom value", seed.germinate().await.unwrap());
+
+        // When every source is missing the value, the error lists each of them
+        let mut seed =
+            Seed::new("%env:GERMINATE_TEST_NOT_OVERRIDDEN || env:GERMINATE_TEST_NOT_OVERRIDDEN%");
+        let err = seed.germinate().await.unwrap_err();
+        assert!(format!("{:#}", err).contains("No value found in any of env:GERMINATE_TEST_NOT_OVERRIDDEN, env:GERMINATE_TEST_NOT_OVERRIDDEN"));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_fallback_chains_past_init_failures() {
+        let mut seed = Seed::new("%failing:a || custom:b%");
+        seed.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+        seed.add_custom_loader(
+            "custom".into(),
+            Box::new(TestLoader::with_value("custom value".into())),
+        );
+
+        assert_eq!("custom value", seed.germinate().await.unwrap());
+
+        // The init failure is returned when none of the other sources have the value
+        std::env::remove_var("GERMINATE_TEST_NOT_FALLBACK");
+        let mut seed = Seed::new("%failing:a || env:GERMINATE_TEST_NOT_FALLBACK%");
+        seed.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+
+        let err = seed.germinate().await.unwrap_err();
+        assert_eq!(
+            "Failed to initialise the 'failing' source: No credentials",
+            format!("{:#}", err)
+        );
+    }
+
+    /// Returns the key it was asked to load as the value
+    struct EchoLoader {}
+
+    #[async_trait::async_trait]
+    impl Loader for EchoLoader {
+        async fn load(&self, key: &str) -> Result<String> {
+            Ok(key.to_string())
+        }
+    }
+
+    /// Returns the key parsed as JSON, to test structured values
+    struct JsonLoader {}
+
+    #[async_trait::async_trait]
+    impl Loader for JsonLoader {
+        async fn load(&self, key: &str) -> Result<String> {
+            Ok(self.load_value(key).await?.to_string())
+        }
+
+        async fn load_value(&self, key: &str) -> Result<Value> {
+            Ok(serde_json::from_str::<serde_json::Value>(key)?.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_nested_placeholders() {
+        std::env::set_var("GERMINATE_TEST_STAGE", "dev");
+        std::env::remove_var("GERMINATE_TEST_NO_STAGE");
+
+        let mut seed = Seed::new("host=%echo:/app/%env:GERMINATE_TEST_STAGE%/db/host%");
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!("host=/app/dev/db/host", seed.germinate().await.unwrap());
+
+        // Failures in nested placeholders are reported against the nested placeholder
+        let mut seed = Seed::new("%echo:/app/%env:GERMINATE_TEST_NO_STAGE%/db/host%");
+        seed.set_error_mode(ErrorMode::CollectAll);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+
+        let err = seed.germinate().await.unwrap_err();
+        let report = err.downcast_ref::<Report>().unwrap();
+        assert!(matches!(
+            report.failures(),
+            [Failure::Load { placeholder, .. }] if placeholder == "%env:GERMINATE_TEST_NO_STAGE%"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_conditionals() {
+        std::env::set_var("GERMINATE_TEST_TLS", "true");
+        std::env::remove_var("GERMINATE_TEST_NO_TLS");
+        std::env::remove_var("GERMINATE_TEST_NO_CERT");
+
+        let template = "listen 443\n%#if env:GERMINATE_TEST_TLS == true%\ntls on\n%#else%\ntls %env:GERMINATE_TEST_NO_CERT%\n%#end%\n";
+        let mut seed = Seed::new(template);
+        assert_eq!("listen 443\ntls on\n", seed.germinate().await.unwrap());
+
+        // Values in branches which aren't rendered aren't loaded, and missing values are false
+        let template = "%#if env:GERMINATE_TEST_NO_TLS%%env:GERMINATE_TEST_NO_CERT%%#elif !env:GERMINATE_TEST_NO_TLS%off%#end%";
+        let mut seed = Seed::new(template);
+        assert_eq!("off", seed.germinate().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_germinate_loops() {
+        std::env::set_var("GERMINATE_TEST_BACKENDS", "10.0.0.1, 10.0.0.2");
+        std::env::remove_var("GERMINATE_TEST_NO_BACKENDS");
+
+        let template = "upstream app {\n%#for i, host in env:GERMINATE_TEST_BACKENDS%\n  server %$host%:%echo:80%$i%%;\n%#end%\n}\n";
+        let mut seed = Seed::new(template);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(
+            "upstream app {\n  server 10.0.0.1:800;\n  server 10.0.0.2:801;\n}\n",
+            seed.germinate().await.unwrap()
+        );
+
+        // Optional lists which don't exist are empty
+        let mut seed = Seed::new("[%#for host in env?:GERMINATE_TEST_NO_BACKENDS%%$host%%#end%]");
+        assert_eq!("[]", seed.germinate().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_germinate_structured_values() {
+        let template = r#"%#for host in json:[{"ip":"10.0.0.1","up":true},{"ip":"10.0.0.2","up":false}]%%#if $host#up%%$host#ip% %#end%%#end%"#;
+        let mut seed = Seed::new(template);
+        seed.add_custom_loader("json".into(), Box::new(JsonLoader {}));
+        assert_eq!("10.0.0.1 ", seed.germinate().await.unwrap());
+
+        // Structured values are written out as JSON when used as a whole
+        let mut seed = Seed::new(r#"%#for host in json:[{"ip":"10.0.0.1"}]%%$host%%#end%"#);
+        seed.add_custom_loader("json".into(), Box::new(JsonLoader {}));
+        assert_eq!(r#"{"ip":"10.0.0.1"}"#, seed.germinate().await.unwrap());
+
+        // Selecting a path which doesn't exist fails the render
+        let mut seed = Seed::new(r#"%#for host in json:[{"ip":"10.0.0.1"}]%%$host#port%%#end%"#);
+        seed.add_custom_loader("json".into(), Box::new(JsonLoader {}));
+        let err = seed.germinate().await.unwrap_err();
+        assert!(format!("{:#}", err).contains("Path 'port' not found"));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_escapes_values() {
+        let template = "[\"%echo:a\"b%\", %echo:c'd%, %echo:1|raw%]";
+        let mut seed = Seed::new(template);
+        seed.set_format(Format::Json);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(r#"["a\"b", "c'd", 1]"#, seed.germinate().await.unwrap());
+
+        let mut seed = Seed::new("echo %echo:$HOME% \"%echo:`id`%\"");
+        seed.set_format(Format::Shell);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(r#"echo '$HOME' "\`id\`""#, seed.germinate().await.unwrap());
+
+        let template = "image: repo/%echo:v1%:latest\nport: %echo:8080%\nname: %echo:a: b%\nscript: |\n  echo \"%echo:it's%\"\n";
+        let mut seed = Seed::new(template);
+        seed.set_format(Format::Yaml);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(
+            "image: repo/v1:latest\nport: 8080\nname: \"a: b\"\nscript: |\n  echo \"it's\"\n",
+            seed.germinate().await.unwrap()
+        );
+
+        let mut seed = Seed::new("cat <<EOF\n%echo:$HOME%\nEOF\n");
+        seed.set_format(Format::Shell);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!("cat <<EOF\n\\$HOME\nEOF\n", seed.germinate().await.unwrap());
+
+        // Values are only escaped when they're written out, not when they're used in keys
+        let mut seed = Seed::new("<a>%echo:%echo:<b>%%</a>");
+        seed.set_format(Format::Xml);
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!("<a>&lt;b&gt;</a>", seed.germinate().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_germinate_structured() {
+        let template = r#"
+replicas: "%json:3%"
+debug: "%json:false%"
+hosts: "%json:[\"a\", \"b\"]%"
+url: "http://%echo:db%:%json:5432%/app"
+missing: "%env?:GERMINATE_TEST_NOT_SET%"
+"#;
+        let mut seed = Seed::new(template);
+        seed.set_format(Format::Yaml);
+        seed.add_custom_loader("json".into(), Box::new(JsonLoader {}));
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(
+            "---\nreplicas: 3\ndebug: false\nhosts:\n  - a\n  - b\nurl: \"http://db:5432/app\"\nmissing: \"\"\n",
+            seed.germinate_structured().await.unwrap()
+        );
+
+        let mut seed = Seed::new("[server]\nport = \"%json:8080%\"\nname = '%echo:a\"b%'\n");
+        seed.set_format(Format::Toml);
+        seed.add_custom_loader("json".into(), Box::new(JsonLoader {}));
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+        assert_eq!(
+            "[server]\nport = 8080\nname = \"a\\\"b\"\n",
+            seed.germinate_structured().await.unwrap()
+        );
+
+        // Failures are collected from every value in the document
+        let mut seed = Seed::new(
+            r#"{"a": "%env:GERMINATE_TEST_NOT_SET%", "b": ["%env:GERMINATE_TEST_NOT_SET_EITHER%"]}"#,
+        );
+        seed.set_format(Format::Json);
+        seed.set_error_mode(ErrorMode::CollectAll);
+        let err = seed.germinate_structured().await.unwrap_err();
+        assert_eq!(2, err.downcast_ref::<Report>().unwrap().failures().len());
+
+        let mut seed = Seed::new("a: b");
+        assert!(seed.germinate_structured().await.is_err());
+    }
+
+    /// Returns the template a few bytes at a time, to test placeholders which are split between
+    /// reads
+    struct ChunkedReader<'t> {
+        template: &'t [u8],
+    }
+
+    impl tokio::io::AsyncRead for ChunkedReader<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let len = self.template.len().min(3).min(buf.remaining());
+            buf.put_slice(&self.template[..len]);
+            self.template = &self.template[len..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_stream() {
+        let template = "café %echo:one%\n%#for x in echo:a,b%\n%echo:%$x%-ü%\n%#end%\n%echo:%echo:two%% 100%\nend";
+        let mut seed = Seed::new("");
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+
+        let mut output = Vec::new();
+        seed.germinate_stream(
+            ChunkedReader {
+                template: template.as_bytes(),
+            },
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            "café one\na-ü\nb-ü\ntwo 100%\nend",
+            String::from_utf8(output).unwrap()
+        );
+
+        // Failures are reported once the whole template has been rendered
+        let mut seed = Seed::new("");
+        seed.set_error_mode(ErrorMode::CollectAll);
+        let mut output = Vec::new();
+        let err = seed
+            .germinate_stream(
+                &b"a %env:GERMINATE_TEST_NOT_SET%\nb\n%#if env:A%"[..],
+                &mut output,
+            )
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Missing %#end%"));
+        assert!(output.is_empty());
+
+        // Nothing is written from the first failure onwards, but every failure is reported
+        let mut seed = Seed::new("");
+        seed.set_error_mode(ErrorMode::CollectAll);
+        let mut output = Vec::new();
+        let err = seed
+            .germinate_stream(
+                ChunkedReader {
+                    template: b"first\nsecond %env:GERMINATE_TEST_NOT_SET% line\n%env:GERMINATE_TEST_NOT_SET%\nlast\n",
+                },
+                &mut output,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(2, err.downcast_ref::<Report>().unwrap().failures().len());
+        assert_eq!(b"first\n", output.as_slice());
+    }
+
+    /// Keeps the size of each write, to test how much of a template is held before it's written
+    #[derive(Default)]
+    struct RecordingWriter {
+        output: Vec<u8>,
+        writes: Vec<usize>,
+    }
+
+    impl tokio::io::AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.output.extend_from_slice(buf);
+            self.writes.push(buf.len());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_stream_bounds_memory() {
+        std::env::set_var("GERMINATE_TEST_STREAM", "value");
+
+        // A long line with no line breaks and a % which is never closed is still written as
+        // it's read, rather than held until the end
+        let filler = "x".repeat(1024 * 1024);
+        let template = format!("100%src:{}%env:GERMINATE_TEST_STREAM%{}", filler, filler);
+        let mut seed = Seed::new("");
+        let mut writer = RecordingWriter::default();
+        seed.germinate_stream(template.as_bytes(), &mut writer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            format!("100%src:{}value{}", filler, filler),
+            String::from_utf8(writer.output).unwrap()
+        );
+        let largest = writer.writes.iter().max().copied().unwrap_or_default();
+        assert!(largest <= STREAM_CHUNK_SIZE + MAX_PLACEHOLDER_LEN);
+    }
+
+    #[test]
+    fn test_seed_and_loaders_are_send() {
+        fn assert_send<T: Send + 'static>() {}
+
+        assert_send::<Seed<'static>>();
+        assert_send::<crate::loader::env::EnvironmentLoader>();
+        #[cfg(feature = "aws")]
+        {
+            assert_send::<crate::loader::awsec2::AwsEc2Loader>();
+            assert_send::<crate::loader::awsec2metadata::AwsEc2MetadataLoader>();
+            assert_send::<crate::loader::awsec2tag::AwsEc2TagLoader>();
+            assert_send::<crate::loader::awsecs::AwsEcsLoader>();
+            assert_send::<crate::loader::awss3::AwsS3Loader>();
+            assert_send::<crate::loader::awsssm::AwsSsmLoader>();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_owned_seed() {
+        let mut seed = Seed::from(String::from("Test %custom:test%"));
+        seed.add_custom_loader(
+            "custom".into(),
+            Box::new(TestLoader::with_value("Test".into())),
+        );
+
+        // The seed and it's render can be moved to another task
+        let mut output = Vec::new();
+        let output = tokio::spawn(async move { seed.render_to(&mut output).await.map(|_| output) })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(b"Test Test", output.as_slice());
+    }
+
+    /// Loads values until it's switched off, after which it fails to initialise and load
+    struct FlakyLoader {
+        online: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl Loader for FlakyLoader {
+        async fn init(&mut self) -> Result<()> {
+            match self.online.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!("Network is unreachable")),
+            }
+        }
+
+        async fn load(&self, key: &str) -> Result<String> {
+            match self.online.load(Ordering::SeqCst) {
+                true => Ok(key.to_uppercase()),
+                false => Err(anyhow::anyhow!("Network is unreachable")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_offline_cache() {
+        let config = OfflineCacheConfig {
+            path: std::env::temp_dir().join(format!("germinate-seed-{}", std::process::id())),
+            key: "8d2a6f1c4b9e0a7d3f5c1e8b2a4d6f0c9e7b5a3d1f8c6e4a2b0d9f7e5c3a1b8d".into(),
+            max_staleness: Duration::from_secs(60),
+        };
+        let online = Arc::new(AtomicBool::new(true));
+        let template = "%flaky:a% %flaky:b%";
+
+        let mut seed = Seed::new(template);
+        seed.set_offline_cache(config.clone()).unwrap();
+        seed.add_custom_loader(
+            "flaky".into(),
+            Box::new(FlakyLoader {
+                online: online.clone(),
+            }),
+        );
+        assert_eq!("A B", seed.germinate().await.unwrap());
+        assert!(seed.report().stale().is_empty());
+
+        // Loading fails, so the cached values are used
+        online.store(false, Ordering::SeqCst);
+        assert_eq!("A B", seed.germinate().await.unwrap());
+        assert_eq!(2, seed.report().stale().len());
+        assert_eq!("a", seed.report().stale()[0].key);
+
+        // A new seed reads the values from the cache file when the source fails to initialise
+        let mut seed = Seed::new("%flaky:a% %flaky:c%");
+        seed.set_offline_cache(config.clone()).unwrap();
+        seed.set_error_mode(ErrorMode::CollectAll);
+        seed.add_custom_loader(
+            "flaky".into(),
+            Box::new(FlakyLoader {
+                online: online.clone(),
+            }),
+        );
+        let err = seed.germinate().await.unwrap_err();
+        let report = err.downcast_ref::<Report>().unwrap();
+        assert_eq!(1, report.stale().len());
+        assert!(matches!(
+            &report.failures()[0],
+            Failure::Init { source, .. } if source == "flaky"
+        ));
+
+        std::fs::remove_file(&config.path).unwrap();
+    }
+
+    /// Fails with a retryable error until it's been called a number of times, and takes a while to
+    /// load a value after that
+    struct SlowLoader {
+        failures: AtomicUsize,
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl Loader for SlowLoader {
+        async fn load(&self, key: &str) -> Result<String> {
+            let remaining = self.failures.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.failures.store(remaining - 1, Ordering::SeqCst);
+                return Err(Retryable::new("ThrottlingException").into());
+            }
+
+            tokio::time::sleep(self.delay).await;
+            Ok(key.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_retries_and_deadline() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let mut seed = Seed::new("%slow:a%");
+        seed.set_retry_policy(policy);
+        seed.add_custom_loader(
+            "slow".into(),
+            Box::new(SlowLoader {
+                failures: AtomicUsize::new(2),
+                delay: Duration::from_millis(0),
+            }),
+        );
+        assert_eq!("a", seed.germinate().await.unwrap());
+
+        // Running out of attempts fails with the last error
+        let mut seed = Seed::new("%slow:a%");
+        seed.set_source_retry_policy("slow", policy);
+        seed.add_custom_loader(
+            "slow".into(),
+            Box::new(SlowLoader {
+                failures: AtomicUsize::new(3),
+                delay: Duration::from_millis(0),
+            }),
+        );
+        let err = seed.germinate().await.unwrap_err();
+        assert_eq!(
+            "Failed to load value: ThrottlingException",
+            format!("{:#}", err)
+        );
+
+        let mut seed = Seed::new("%slow:a%");
+        seed.set_deadline(Duration::from_millis(50));
+        seed.set_retry_policy(RetryPolicy {
+            timeout: None,
+            ..policy
+        });
+        seed.add_custom_loader(
+            "slow".into(),
+            Box::new(SlowLoader {
+                failures: AtomicUsize::new(0),
+                delay: Duration::from_secs(10),
+            }),
+        );
+        let err = seed.germinate().await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("didn't finish within the 50ms deadline"));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_rate_limit() {
+        let mut seed = Seed::new("%slow:a% %slow:b%");
+        seed.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        });
+        seed.set_rate_limit(
+            "slow",
+            RateLimit {
+                per_second: 20.0,
+                burst: 1,
+            },
+        )
+        .unwrap();
+        seed.add_custom_loader(
+            "slow".into(),
+            Box::new(SlowLoader {
+                failures: AtomicUsize::new(1),
+                delay: Duration::from_millis(0),
+            }),
+        );
+
+        // The retry takes a token too, so the three loads wait for two tokens
+        let start = std::time::Instant::now();
+        assert_eq!("a b", seed.germinate().await.unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Values served from the cache don't take tokens
+        let mut seed = Seed::new("%echo:a% %echo:b%");
+        seed.set_cache("echo", CacheConfig::default());
+        seed.set_rate_limit(
+            "echo",
+            RateLimit {
+                per_second: 1.0,
+                burst: 2,
+            },
+        )
+        .unwrap();
+        seed.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+
+        assert_eq!("a b", seed.germinate().await.unwrap());
+        let start = std::time::Instant::now();
+        assert_eq!("a b", seed.germinate().await.unwrap());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    /// Returns the key in upper case as a secret, except keys starting with `/` which aren't found
+    /// and keys starting with `list/`, which return a list holding the secret
+    struct SecretLoader {}
+
+    #[async_trait::async_trait]
+    impl Loader for SecretLoader {
+        async fn load(&self, key: &str) -> Result<String> {
+            Ok(self.load_value(key).await?.expose())
+        }
+
+        async fn load_value(&self, key: &str) -> Result<Value> {
+            if key.starts_with('/') {
+                return Err(NotFound::new("Secret not found").into());
+            }
+
+            match key.strip_prefix("list/") {
+                Some(key) => Ok(Value::List(vec![
+                    Value::from("user"),
+                    Value::Secret(Secret::new(key.to_uppercase())),
+                ])),
+                None => Ok(Value::Secret(Secret::new(key.to_uppercase()))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_germinate_secrets() {
+        let mut seed = Seed::new("password: %secret:hunter2%");
+        seed.add_custom_loader("secret".into(), Box::new(SecretLoader {}));
+
+        assert_eq!("password: HUNTER2", seed.germinate().await.unwrap());
+
+        // Secrets are kept in the replacements, and only masked in the redacted ones
+        let values = seed.parse().await.unwrap();
+        assert_eq!("HUNTER2", values["%secret:hunter2%"]);
+        let values = seed.parse_redacted().await.unwrap();
+        assert_eq!("********", values["%secret:hunter2%"]);
+
+        // A value holding a secret is kept secret as a whole
+        let mut seed = Seed::new("users: %secret:list/hunter2%");
+        seed.add_custom_loader("secret".into(), Box::new(SecretLoader {}));
+
+        assert_eq!("users: user,HUNTER2", seed.germinate().await.unwrap());
+        let values = seed.parse_redacted().await.unwrap();
+        assert_eq!("********", values["%secret:list/hunter2%"]);
+
+        // and in keys shown in the report
+        let mut seed = Seed::new("%secret:/app/%secret:hunter2%%");
+        seed.set_error_mode(ErrorMode::CollectAll);
+        seed.add_custom_loader("secret".into(), Box::new(SecretLoader {}));
+
+        let err = seed.germinate().await.unwrap_err();
+        let report = err.downcast_ref::<Report>().unwrap();
+        match &report.failures()[0] {
+            Failure::Load { key, .. } => assert_eq!("/app/********", key),
+            failure => panic!("Unexpected failure {:?}", failure),
+        }
+        assert!(!report.to_string().contains("HUNTER2"));
+    }
+
+    #[tokio::test]
+    async fn test_germinate_shared_registry() {
+        let registry = LoaderRegistry::new();
+        registry.add_custom_loader("echo".into(), Box::new(EchoLoader {}));
+
+        let mut first = Seed::new("%echo:first%");
+        first.set_loader_registry(registry.clone());
+        let mut second = Seed::new("%echo:second%");
+        second.set_loader_registry(registry.clone());
+
+        assert_eq!("first", first.germinate().await.unwrap());
+        assert_eq!("second", second.germinate().await.unwrap());
+
+        // Loaders added through a seed are added to it's registry
+        let mut third = Seed::new("%failing:third%");
+        third.set_loader_registry(registry.clone());
+        third.add_custom_loader("failing".into(), Box::new(FailingLoader {}));
+        assert!(first
+            .loader_registry()
+            .get(&Source::from("failing"))
+            .await
+            .is_err());
+
+        // The loader which failed to initialise is kept, and initialised again by the next seed
+        let err = third.germinate().await.unwrap_err();
+        assert_eq!(
+            "Failed to initialise the 'failing' source: No credentials",
+            format!("{:#}", err)
+        );
+    }
+
     #[tokio::test]
     async fn test_geminate_with_custom_loader() {
         let mut seed = Seed::new("Test %custom:test% Test");
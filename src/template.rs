@@ -0,0 +1,1360 @@
+//! Parses template strings into the text and placeholders they're made up of
+//!
+//! A placeholder starts with a `%` followed by a list of sources and a `:`, e.g. `%env,awsssm?:`,
+//! and ends at the next `%` which doesn't start another placeholder. This means placeholders can be
+//! nested inside the key of another placeholder, e.g. `%awsssm:/app/%env:STAGE%/db/host%`. A `%`
+//! which isn't part of a placeholder is left in the output as it is
+//!
+//! Sections of the template can be included conditionally with block tags, e.g.
+//! `%#if env:TLS_ENABLED == true%`, `%#elif ...%`, `%#else%` and `%#end%`, or repeated for each
+//! element of a list with `%#for i, host in env:BACKENDS%` and `%#end%`. The loop variables are
+//! written as `%$host%`, and can be used anywhere a placeholder can. Outside of a loop which
+//! defines it, `%$host%` is left as text. A field can be selected from a structured variable with
+//! a `#` suffix, e.g. `%$host#PrivateIpAddress%`. A block tag on a line of it's own is removed
+//! along with the rest of the line
+//!
+//! Placeholders and variables can end with `|raw`, e.g. `%env:PORT|raw%`, to write their value
+//! without escaping it for the output format
+use crate::loader::Source;
+use anyhow::{bail, Result};
+
+/// The maximum depth placeholders can be nested inside each other
+pub(crate) const MAX_DEPTH: usize = 8;
+
+/// The maximum length of a placeholder, including any placeholders nested in it. Anything longer
+/// is read as text, so a stray `%` followed by a source, e.g. `100%http://`, can't make the rest
+/// of the template part of a placeholder
+pub(crate) const MAX_PLACEHOLDER_LEN: usize = 16 * 1024;
+
+/// A part of a parsed template
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Node {
+    Text(String),
+    Placeholder(Placeholder),
+    /// The value of a loop variable
+    Variable(Variable),
+    /// Renders the nodes of the first branch whose condition is true, or the otherwise nodes if
+    /// none of them are
+    Conditional {
+        branches: Vec<(Condition, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    /// Renders the body once for each element of the list loaded by the placeholder, with the
+    /// element and optionally it's index available as variables
+    Loop {
+        index: Option<String>,
+        item: String,
+        placeholder: Placeholder,
+        body: Vec<Node>,
+    },
+}
+
+/// The condition of an `%#if%` or `%#elif%` tag. The subject is loaded and either compared
+/// against the given value, or checked for being truthy
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Condition {
+    pub(crate) negate: bool,
+    pub(crate) subject: Subject,
+    pub(crate) comparison: Option<Comparison>,
+}
+
+/// The value a condition checks
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Subject {
+    Placeholder(Placeholder),
+    Variable(Variable),
+}
+
+/// Compares the loaded value of a condition against a literal value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Comparison {
+    Equal(String),
+    NotEqual(String),
+}
+
+impl Condition {
+    /// Checks whether the condition holds for the loaded value, where None means the value wasn't
+    /// found. Values are truthy unless they are missing, empty, `false` or `0`
+    pub(crate) fn evaluate(&self, value: Option<&str>) -> bool {
+        let result = match &self.comparison {
+            None => !matches!(value, None | Some("") | Some("false") | Some("0")),
+            Some(Comparison::Equal(expected)) => value == Some(expected.as_str()),
+            Some(Comparison::NotEqual(expected)) => value != Some(expected.as_str()),
+        };
+
+        result != self.negate
+    }
+}
+
+/// The nodes parsed up to the end of a block, the position after them, and the tag which ended the
+/// block along with how it appears in the template
+type ParsedNodes<'t> = (Vec<Node>, usize, Option<(Tag, &'t str)>);
+
+/// The block tags which can appear in a template
+enum Tag {
+    If(Condition),
+    Elif(Condition),
+    Else,
+    For {
+        index: Option<String>,
+        item: String,
+        placeholder: Placeholder,
+    },
+    End,
+}
+
+/// A placeholder found in the template, made up of one or more sources to try in order along with
+/// the key to load from each of them. Sources can either share a key, e.g. `env,awsssm:DB_HOST`,
+/// or each have their own, e.g. `env:DB_HOST || awsssm:/app/db/host`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Placeholder {
+    /// The placeholder as it appears in the template, including the `%` delimiters
+    pub(crate) raw: String,
+    pub(crate) alternatives: Vec<Alternative>,
+    pub(crate) optional: bool,
+    /// False when the placeholder ends with `|raw`, so it's value is written as it is
+    pub(crate) escape: bool,
+}
+
+impl Placeholder {
+    /// Returns true if the key of any alternative uses a loop variable, in which case the value
+    /// can differ each time the placeholder is rendered
+    pub(crate) fn uses_variables(&self) -> bool {
+        self.alternatives
+            .iter()
+            .flat_map(|alternative| &alternative.key)
+            .any(|segment| match segment {
+                Segment::Text(_) => false,
+                Segment::Placeholder(nested) => nested.uses_variables(),
+                Segment::Variable(_) => true,
+            })
+    }
+}
+
+/// One of the sources to try when loading the value for a placeholder
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Alternative {
+    pub(crate) source: Source,
+    pub(crate) key: Vec<Segment>,
+}
+
+impl std::fmt::Display for Alternative {
+    /// Writes the alternative as it appears in the template, e.g. `env:DB_HOST`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.source)?;
+        for segment in &self.key {
+            match segment {
+                Segment::Text(text) => f.write_str(text)?,
+                Segment::Placeholder(nested) => f.write_str(&nested.raw)?,
+                Segment::Variable(variable) => write!(f, "%{}%", variable)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A part of a placeholder's key. Keys containing nested placeholders are only known once the
+/// values of the nested placeholders have been loaded
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+    Variable(Variable),
+}
+
+/// A reference to a loop variable, optionally selecting a field from it's value with a dot
+/// separated path, e.g. `$host#Tags.Name`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Variable {
+    pub(crate) name: String,
+    pub(crate) path: Option<String>,
+    /// False when the variable ends with `|raw`, so it's value is written as it is
+    pub(crate) escape: bool,
+}
+
+impl std::fmt::Display for Variable {
+    /// Writes the variable as it appears in the template without the `%` delimiters, e.g. `$host`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "${}", self.name)?;
+        if let Some(path) = &self.path {
+            write!(f, "#{}", path)?;
+        }
+        if !self.escape {
+            f.write_str("|raw")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the template into a list of text, placeholder and block nodes
+pub(crate) fn parse(template: &str) -> Result<Vec<Node>> {
+    match parse_nodes(template, 0, &mut Vec::new())? {
+        (nodes, _, None) => Ok(nodes),
+        (_, _, Some((_, raw))) => bail!("Unexpected {} outside of a block", raw),
+    }
+}
+
+/// Finds how much of the start of a template which is still being read can be parsed and rendered
+/// without the rest of it. This is used when streaming. It remembers how far it's scanned, so the
+/// template is only scanned once however many times more of it is read
+#[derive(Debug, Default)]
+pub(crate) struct Splitter {
+    /// How far the template has been scanned
+    pos: usize,
+    /// The loop variables defined at `pos`
+    scope: Vec<String>,
+    /// How many loop variables were defined when each of the blocks open at `pos` started
+    blocks: Vec<usize>,
+    /// The end of the last line break outside of any block or placeholder
+    complete: usize,
+    /// The start and end of the last placeholder, variable or tag outside of any block
+    last: Option<(usize, usize)>,
+}
+
+impl Splitter {
+    /// Returns the length of the longest start of the template which ends at a line break outside
+    /// of any block or placeholder. A line longer than a placeholder can be is split before it's
+    /// last placeholder, or at the end of it's text, so it doesn't have to be held whole
+    ///
+    /// The template must be the same as the last time, with more read onto the end of it and
+    /// anything passed to [`split`](#method.split) removed from the start
+    pub(crate) fn complete_len(&mut self, template: &str) -> usize {
+        self.scan(template);
+
+        if self.blocks.is_empty() && self.pos - self.complete > MAX_PLACEHOLDER_LEN {
+            // The text following a value decides how it's escaped, so the line isn't split
+            // straight after one
+            self.complete = match self.last {
+                Some((start, end)) if end == self.pos => start.max(self.complete),
+                _ => self.pos,
+            };
+        }
+
+        self.complete
+    }
+
+    /// Removes the given length from the start of the template, after it's been rendered
+    pub(crate) fn split(&mut self, len: usize) {
+        self.pos = self.pos.saturating_sub(len);
+        self.complete = self.complete.saturating_sub(len);
+        self.last = self
+            .last
+            .filter(|(start, _)| *start >= len)
+            .map(|(start, end)| (start - len, end - len));
+    }
+
+    /// Scans the template from where it was left, stopping at anything which may just be cut off
+    fn scan(&mut self, template: &str) {
+        loop {
+            let next = template[self.pos..].find('%').map(|i| self.pos + i);
+
+            // Any line break before the next % is outside of a placeholder
+            if self.blocks.is_empty() {
+                let end = next.unwrap_or(template.len());
+                if let Some(i) = template[self.pos..end].rfind('\n') {
+                    self.complete = self.pos + i + 1;
+                }
+            }
+
+            let start = match next {
+                Some(start) => start,
+                None => {
+                    self.pos = template.len();
+                    return;
+                }
+            };
+
+            // Once there's more than a placeholder can hold after a %, reading more of the
+            // template won't change what it is
+            let whole = template.len() - start > MAX_PLACEHOLDER_LEN;
+            if !whole && cut_off(&template[start..]) {
+                self.pos = start;
+                return;
+            }
+
+            // A tag which fails to parse may just be cut off. If it's invalid, the error is
+            // returned once it's rendered
+            match parse_tag(template, start, &self.scope) {
+                Ok(Some((tag, end))) => {
+                    if self.blocks.is_empty() {
+                        self.last = Some((start, end));
+                    }
+                    match tag {
+                        Tag::If(_) => self.blocks.push(self.scope.len()),
+                        Tag::For { index, item, .. } => {
+                            self.blocks.push(self.scope.len());
+                            self.scope.push(item);
+                            self.scope.extend(index);
+                        }
+                        Tag::End => {
+                            if let Some(len) = self.blocks.pop() {
+                                self.scope.truncate(len);
+                            }
+                        }
+                        Tag::Elif(_) | Tag::Else => {}
+                    }
+                    self.pos = end;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(_) if whole => {
+                    self.pos = start + 1;
+                    continue;
+                }
+                Err(_) => {
+                    self.pos = start;
+                    return;
+                }
+            }
+
+            let end = match parse_variable(template, start, &self.scope) {
+                Some((_, end)) => Some(end),
+                None => match parse_placeholder(template, start, start + 1, 0, &self.scope) {
+                    Ok(Some((_, end))) => Some(end),
+                    Ok(None) if whole || source_spec(&template[start + 1..]).is_none() => None,
+                    // The placeholder could be closed by the rest of the template
+                    Ok(None) => {
+                        self.pos = start;
+                        return;
+                    }
+                    Err(_) if whole => None,
+                    Err(_) => {
+                        self.pos = start;
+                        return;
+                    }
+                },
+            };
+
+            match end {
+                Some(end) => {
+                    if self.blocks.is_empty() {
+                        self.last = Some((start, end));
+                    }
+                    self.pos = end;
+                }
+                None => self.pos = start + 1,
+            }
+        }
+    }
+}
+
+/// Whether the text from a % to the end of the template could be the start of a tag, variable or
+/// placeholder which has been cut off, e.g. `%#fo` or `%env`
+fn cut_off(text: &str) -> bool {
+    let tag = ["%#else%", "%#end%", "%#for ", "%#if ", "%#elif "]
+        .iter()
+        .any(|tag| tag.starts_with(text));
+    let variable = text.starts_with("%$") && !text[2..].contains(['%', ' ', '\n']);
+    let sources = text[1..]
+        .trim_end_matches('?')
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == ',');
+
+    tag || variable || sources
+}
+
+/// Parses nodes from the given position until either the end of the template or a block tag
+/// which belongs to an enclosing block, where the scope is the loop variables defined there.
+/// Returns the nodes, the position after the last one, and the tag which ended them along with
+/// how it appears in the template
+fn parse_nodes<'t>(
+    template: &'t str,
+    mut pos: usize,
+    scope: &mut Vec<String>,
+) -> Result<ParsedNodes<'t>> {
+    let mut nodes = Vec::new();
+    let mut text_start = pos;
+
+    while let Some(offset) = template[pos..].find('%') {
+        let start = pos + offset;
+
+        if let Some((tag, end)) = parse_tag(template, start, scope)? {
+            let raw = &template[start..end];
+
+            // Remove the whole line when the tag is on a line of it's own
+            let (text_end, end) = match standalone(template, text_start, start, end) {
+                Some((line_start, line_end)) => (line_start, line_end),
+                None => (start, end),
+            };
+            if text_start < text_end {
+                nodes.push(Node::Text(template[text_start..text_end].to_string()));
+            }
+
+            match tag {
+                Tag::If(condition) => {
+                    let (node, block_end) =
+                        parse_conditional(template, condition, raw, end, scope)?;
+                    nodes.push(node);
+                    pos = block_end;
+                    text_start = block_end;
+                }
+                Tag::For {
+                    index,
+                    item,
+                    placeholder,
+                } => {
+                    // The loop variables are only defined inside the loop
+                    let len = scope.len();
+                    scope.push(item.clone());
+                    scope.extend(index.clone());
+                    let body = parse_nodes(template, end, scope);
+                    scope.truncate(len);
+
+                    let (body, block_end, tag) = body?;
+                    match tag {
+                        Some((Tag::End, _)) => {}
+                        Some((_, tag)) => bail!("Unexpected {} in {}", tag, raw),
+                        None => bail!("Missing %#end% for {}", raw),
+                    }
+
+                    nodes.push(Node::Loop {
+                        index,
+                        item,
+                        placeholder,
+                        body,
+                    });
+                    pos = block_end;
+                    text_start = block_end;
+                }
+                tag => return Ok((nodes, end, Some((tag, raw)))),
+            }
+
+            continue;
+        }
+
+        if let Some((variable, end)) = parse_variable(template, start, scope) {
+            if text_start < start {
+                nodes.push(Node::Text(template[text_start..start].to_string()));
+            }
+            nodes.push(Node::Variable(variable));
+            pos = end;
+            text_start = end;
+            continue;
+        }
+
+        match parse_placeholder(template, start, start + 1, 0, scope)? {
+            Some((placeholder, end)) => {
+                if text_start < start {
+                    nodes.push(Node::Text(template[text_start..start].to_string()));
+                }
+                nodes.push(Node::Placeholder(placeholder));
+                pos = end;
+                text_start = end;
+            }
+            None => pos = start + 1,
+        }
+    }
+
+    if text_start < template.len() {
+        nodes.push(Node::Text(template[text_start..].to_string()));
+    }
+
+    Ok((nodes, template.len(), None))
+}
+
+/// Parses the branches of an `%#if%` block up to and including it's `%#end%` tag
+fn parse_conditional(
+    template: &str,
+    condition: Condition,
+    raw: &str,
+    pos: usize,
+    scope: &mut Vec<String>,
+) -> Result<(Node, usize)> {
+    let mut branches = Vec::new();
+    let mut condition = Some(condition);
+    let mut pos = pos;
+
+    loop {
+        let (nodes, end, tag) = parse_nodes(template, pos, scope)?;
+        pos = end;
+
+        match condition.take() {
+            Some(condition) => branches.push((condition, nodes)),
+            // Only the %#else% branch has no condition, and it must be followed by %#end%
+            None => match tag {
+                Some((Tag::End, _)) => {
+                    return Ok((
+                        Node::Conditional {
+                            branches,
+                            otherwise: nodes,
+                        },
+                        pos,
+                    ))
+                }
+                Some((_, tag)) => bail!("Unexpected {} after %#else% in {}", tag, raw),
+                None => bail!("Missing %#end% for {}", raw),
+            },
+        }
+
+        match tag {
+            Some((Tag::Elif(next), _)) => condition = Some(next),
+            Some((Tag::Else, _)) => {}
+            Some((Tag::End, _)) => {
+                return Ok((
+                    Node::Conditional {
+                        branches,
+                        otherwise: Vec::new(),
+                    },
+                    pos,
+                ))
+            }
+            Some((Tag::If(_), _)) | Some((Tag::For { .. }, _)) => {
+                unreachable!("parse_nodes handles nested blocks")
+            }
+            None => bail!("Missing %#end% for {}", raw),
+        }
+    }
+}
+
+/// Attempts to parse a block tag starting at the `%` at the given position, returning the tag
+/// and the position just after it's closing `%`
+fn parse_tag(template: &str, start: usize, scope: &[String]) -> Result<Option<(Tag, usize)>> {
+    let rest = &template[start..];
+
+    if rest.starts_with("%#else%") {
+        return Ok(Some((Tag::Else, start + 7)));
+    }
+    if rest.starts_with("%#end%") {
+        return Ok(Some((Tag::End, start + 6)));
+    }
+
+    if rest.starts_with("%#for ") {
+        return parse_loop(template, start, start + 6, scope).map(Some);
+    }
+
+    let (body_start, elif) = if rest.starts_with("%#if ") {
+        (start + 5, false)
+    } else if rest.starts_with("%#elif ") {
+        (start + 7, true)
+    } else {
+        return Ok(None);
+    };
+
+    let (condition, end) = parse_condition(template, start, body_start, scope)?;
+    let tag = match elif {
+        true => Tag::Elif(condition),
+        false => Tag::If(condition),
+    };
+
+    Ok(Some((tag, end)))
+}
+
+/// Parses the header of a loop such as `host in env:BACKENDS` or `i, host in env:BACKENDS` from
+/// the body of a tag
+fn parse_loop(
+    template: &str,
+    start: usize,
+    body_start: usize,
+    scope: &[String],
+) -> Result<(Tag, usize)> {
+    let invalid = || invalid_tag(template, start, "loop");
+
+    let (first, rest) = split_name(template[body_start..].trim_start()).ok_or_else(invalid)?;
+    let (index, item, rest) = match rest.trim_start().strip_prefix(',') {
+        Some(rest) => {
+            let (item, rest) = split_name(rest.trim_start()).ok_or_else(invalid)?;
+            (Some(first.to_string()), item.to_string(), rest)
+        }
+        None => (None, first.to_string(), rest),
+    };
+
+    let rest = rest
+        .trim_start()
+        .strip_prefix("in ")
+        .ok_or_else(invalid)?
+        .trim_start();
+
+    // rest is always a suffix of the template, so it's length gives it's position
+    let (placeholder, end) =
+        parse_placeholder(template, start, template.len() - rest.len(), 0, scope)?
+            .ok_or_else(invalid)?;
+
+    let tag = Tag::For {
+        index,
+        item,
+        placeholder,
+    };
+
+    Ok((tag, end))
+}
+
+/// Parses a condition such as `!env:DEBUG`, `env:STAGE == prod` or `$i != 0` from the body of a
+/// tag
+fn parse_condition(
+    template: &str,
+    start: usize,
+    body_start: usize,
+    scope: &[String],
+) -> Result<(Condition, usize)> {
+    let invalid = || invalid_tag(template, start, "condition");
+
+    let trimmed = template[body_start..].trim_start();
+    let (negate, trimmed) = match trimmed.strip_prefix('!') {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, trimmed),
+    };
+
+    // Conditions on loop variables are written as $name or $name#path
+    if let Some(rest) = trimmed.strip_prefix('$') {
+        let (variable, rest) = split_variable(rest).ok_or_else(invalid)?;
+        if !scope.contains(&variable.name) {
+            bail!(
+                "Unknown variable '${}', variables are only defined inside a loop",
+                variable.name
+            );
+        }
+        let close = rest.find('%').ok_or_else(invalid)?;
+
+        let mut text = rest[..close].to_string();
+        let comparison = split_comparison(&mut text);
+        if !text.trim().is_empty() {
+            return Err(invalid());
+        }
+
+        let end = template.len() - rest.len() + close + 1;
+        let condition = Condition {
+            negate,
+            subject: Subject::Variable(variable),
+            comparison,
+        };
+
+        return Ok((condition, end));
+    }
+
+    let (mut placeholder, end) =
+        parse_placeholder(template, start, template.len() - trimmed.len(), 0, scope)?
+            .ok_or_else(invalid)?;
+
+    // The comparison is written after the key of the last alternative
+    let mut comparison = None;
+    // Unwrap is safe as a placeholder always has at least one alternative
+    let original = placeholder.alternatives.last().unwrap().key.clone();
+    let mut key = original.clone();
+
+    if let Some(Segment::Text(text)) = key.last_mut() {
+        comparison = split_comparison(text);
+    }
+    key.retain(|segment| !matches!(segment, Segment::Text(text) if text.is_empty()));
+
+    // Sources listed together, e.g. `env,awsssm:KEY == value`, share the key and comparison
+    for alternative in &mut placeholder.alternatives {
+        if alternative.key == original {
+            alternative.key = key.clone();
+        }
+    }
+
+    if key.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((
+        Condition {
+            negate,
+            subject: Subject::Placeholder(placeholder),
+            comparison,
+        },
+        end,
+    ))
+}
+
+/// Removes a trailing `== value` or `!= value` comparison from the text
+fn split_comparison(text: &mut String) -> Option<Comparison> {
+    let (i, equal) = [("==", true), ("!=", false)]
+        .iter()
+        .filter_map(|(op, equal)| text.find(op).map(|i| (i, *equal)))
+        .min()?;
+
+    let expected = text[i + 2..].trim().to_string();
+    *text = text[..i].trim_end().to_string();
+
+    match equal {
+        true => Some(Comparison::Equal(expected)),
+        false => Some(Comparison::NotEqual(expected)),
+    }
+}
+
+/// The error for a block tag which can't be parsed, showing the line it's on
+fn invalid_tag(template: &str, start: usize, what: &str) -> anyhow::Error {
+    let line_end = template[start..]
+        .find('\n')
+        .map_or(template.len(), |i| start + i);
+    anyhow::anyhow!("Invalid {} in '{}'", what, &template[start..line_end])
+}
+
+/// Splits a variable name from the start of the text, returning it and the remaining text
+fn split_name(text: &str) -> Option<(&str, &str)> {
+    let len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+
+    match text[..len].chars().next() {
+        Some(c) if !c.is_ascii_digit() => Some(text.split_at(len)),
+        _ => None,
+    }
+}
+
+/// Splits a variable name and optional `#` path from the start of the text, returning the
+/// variable and the remaining text
+fn split_variable(text: &str) -> Option<(Variable, &str)> {
+    let (name, rest) = split_name(text)?;
+
+    let (path, rest) = match rest.strip_prefix('#') {
+        Some(rest) => {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                return None;
+            }
+            (Some(rest[..len].to_string()), &rest[len..])
+        }
+        None => (None, rest),
+    };
+
+    let variable = Variable {
+        name: name.to_string(),
+        path,
+        escape: true,
+    };
+
+    Some((variable, rest))
+}
+
+/// Attempts to parse a loop variable such as `%$host%` or `%$host#PrivateIpAddress%` starting at
+/// the `%` at the given position, returning the variable and the position just after it's closing
+/// `%`. Variables which aren't in scope are text, e.g. `100%$HOME%` outside of a loop
+fn parse_variable(template: &str, start: usize, scope: &[String]) -> Option<(Variable, usize)> {
+    let (mut variable, mut rest) = split_variable(template[start..].strip_prefix("%$")?)?;
+    if !scope.contains(&variable.name) {
+        return None;
+    }
+    if let Some(after) = rest.strip_prefix("|raw") {
+        variable.escape = false;
+        rest = after;
+    }
+    rest.strip_prefix('%')?;
+
+    Some((variable, template.len() - rest.len() + 1))
+}
+
+/// When a tag between the given positions is the only thing on it's line, returns the positions
+/// of the start of the line and the start of the next line
+fn standalone(
+    template: &str,
+    text_start: usize,
+    start: usize,
+    end: usize,
+) -> Option<(usize, usize)> {
+    let line_start = template[..start].rfind('\n').map_or(0, |i| i + 1);
+    if line_start < text_start || !template[line_start..start].trim().is_empty() {
+        return None;
+    }
+
+    let line_end = template[end..]
+        .find('\n')
+        .map_or(template.len(), |i| end + i + 1);
+    if !template[end..line_end].trim().is_empty() {
+        return None;
+    }
+
+    Some((line_start, line_end))
+}
+
+/// Attempts to parse a placeholder starting at the `%` at the given position, with it's list of
+/// sources starting at body_start. Returns the placeholder and the position just after it's
+/// closing `%`, or None if there isn't a placeholder at the position
+fn parse_placeholder(
+    template: &str,
+    start: usize,
+    body_start: usize,
+    depth: usize,
+    scope: &[String],
+) -> Result<Option<(Placeholder, usize)>> {
+    let (sources, optional, len) = match source_spec(&template[body_start..]) {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    if depth >= MAX_DEPTH {
+        bail!(
+            "Placeholders can't be nested more than {} levels deep",
+            MAX_DEPTH
+        );
+    }
+
+    let mut parts = vec![(sources, optional, Vec::new())];
+    let mut text = String::new();
+    let mut pos = body_start + len;
+    // Where the placeholder could have been closed instead of starting a nested placeholder. If
+    // it's never closed, text which only looked nested is read the same way as before nesting was
+    // supported, e.g. `%env:A%x:%env:B% 100%` is `%env:A%` and `%env:B%` rather than
+    // `%x:%env:B% 100%`. Nested placeholders don't fall back, so that it's the outermost one
+    // which closes early
+    let mut close_instead = None;
+
+    // Placeholders can't be longer than MAX_PLACEHOLDER_LEN
+    let mut limit = (start + MAX_PLACEHOLDER_LEN).min(template.len());
+    while !template.is_char_boundary(limit) {
+        limit -= 1;
+    }
+
+    let end = loop {
+        // A nested placeholder which ends past the limit leaves nothing to read
+        let rest = template.get(pos..limit).unwrap_or_default();
+        let next = match rest.find(['%', '|']) {
+            Some(next) => next,
+            None => match close_instead.take() {
+                Some((close_parts, close_text, close)) => {
+                    parts = close_parts;
+                    text = close_text;
+                    push_text(&mut parts, &mut text);
+                    break close + 1;
+                }
+                // The placeholder is never closed
+                None => return Ok(None),
+            },
+        };
+
+        text.push_str(&rest[..next]);
+        pos += next;
+
+        if rest[next..].starts_with("||") {
+            // A || followed by a list of sources starts the next alternative, otherwise it's
+            // part of the key
+            match source_spec(template[pos + 2..].trim_start()) {
+                Some((sources, optional, len)) => {
+                    push_text(&mut parts, &mut text);
+                    parts.push((sources, optional, Vec::new()));
+
+                    let skipped =
+                        template[pos + 2..].len() - template[pos + 2..].trim_start().len();
+                    pos += 2 + skipped + len;
+                }
+                None => {
+                    text.push_str("||");
+                    pos += 2;
+                }
+            }
+        } else if rest[next..].starts_with('|') {
+            text.push('|');
+            pos += 1;
+        } else {
+            // A % either starts a loop variable, a nested placeholder, or closes this one
+            if let Some((variable, variable_end)) = parse_variable(template, pos, scope) {
+                push_text(&mut parts, &mut text);
+                // Unwrap is safe as there's always at least one part
+                let (_, _, key) = parts.last_mut().unwrap();
+                key.push(Segment::Variable(variable));
+                pos = variable_end;
+                continue;
+            }
+
+            match parse_placeholder(template, pos, pos + 1, depth + 1, scope)? {
+                Some((nested, nested_end)) => {
+                    // Unwrap is safe as there's always at least one part
+                    let has_key = !text.is_empty() || !parts.last().unwrap().2.is_empty();
+                    if depth == 0 && close_instead.is_none() && has_key {
+                        close_instead = Some((parts.clone(), text.clone(), pos));
+                    }
+
+                    push_text(&mut parts, &mut text);
+                    // Unwrap is safe as there's always at least one part
+                    let (_, _, key) = parts.last_mut().unwrap();
+                    key.push(Segment::Placeholder(nested));
+                    pos = nested_end;
+                }
+                None => {
+                    push_text(&mut parts, &mut text);
+                    break pos + 1;
+                }
+            }
+        }
+    };
+
+    // A trailing |raw turns off escaping rather than being part of the key
+    let mut escape = true;
+    // Unwrap is safe as there's always at least one part
+    let (_, _, key) = parts.last_mut().unwrap();
+    if let Some(Segment::Text(text)) = key.last_mut() {
+        if let Some(stripped) = text.strip_suffix("|raw") {
+            *text = stripped.to_string();
+            escape = false;
+        }
+    }
+    key.retain(|segment| !matches!(segment, Segment::Text(text) if text.is_empty()));
+
+    // Placeholders must have a key
+    if parts[0].2.is_empty() {
+        return Ok(None);
+    }
+
+    // Whitespace around the || separator isn't part of the key
+    if parts.len() > 1 {
+        for (_, _, key) in parts.iter_mut() {
+            trim(key);
+        }
+    }
+
+    let mut alternatives = Vec::new();
+    for (sources, _, key) in &parts {
+        for source in sources.split(',') {
+            alternatives.push(Alternative {
+                source: Source::from(source),
+                key: key.clone(),
+            });
+        }
+    }
+
+    let placeholder = Placeholder {
+        raw: template[start..end].to_string(),
+        alternatives,
+        optional: parts.iter().any(|(_, optional, _)| *optional),
+        escape,
+    };
+
+    Ok(Some((placeholder, end)))
+}
+
+/// Moves any buffered text onto the key of the last part
+fn push_text(parts: &mut [(&str, bool, Vec<Segment>)], text: &mut String) {
+    if !text.is_empty() {
+        // Unwrap is safe as there's always at least one part
+        let (_, _, key) = parts.last_mut().unwrap();
+        key.push(Segment::Text(std::mem::take(text)));
+    }
+}
+
+/// Trims the whitespace from the start and end of a key
+fn trim(key: &mut Vec<Segment>) {
+    if let Some(Segment::Text(text)) = key.first_mut() {
+        *text = text.trim_start().to_string();
+    }
+    if let Some(Segment::Text(text)) = key.last_mut() {
+        *text = text.trim_end().to_string();
+    }
+    key.retain(|segment| !matches!(segment, Segment::Text(text) if text.is_empty()));
+}
+
+/// Matches a list of sources at the start of the text, e.g. `env,awsssm?:`, returning the sources,
+/// whether the placeholder is marked optional, and the length of the match including the `:`
+fn source_spec(text: &str) -> Option<(&str, bool, usize)> {
+    let sources_len = text
+        .find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == ','))
+        .unwrap_or(text.len());
+    let sources = &text[..sources_len];
+
+    if sources.split(',').any(str::is_empty) {
+        return None;
+    }
+
+    match &text[sources_len..] {
+        rest if rest.starts_with("?:") => Some((sources, true, sources_len + 2)),
+        rest if rest.starts_with(':') => Some((sources, false, sources_len + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes the alternatives of a placeholder out as `source:key`, with nested placeholders
+    /// wrapped in brackets
+    fn alternatives(placeholder: &Placeholder) -> Vec<String> {
+        placeholder
+            .alternatives
+            .iter()
+            .map(|alternative| {
+                let key = alternative
+                    .key
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Text(text) => text.clone(),
+                        Segment::Placeholder(nested) => {
+                            format!("[{}]", alternatives(nested).join("|"))
+                        }
+                        Segment::Variable(variable) => variable.to_string(),
+                    })
+                    .collect::<String>();
+                format!("{}:{}", alternative.source, key)
+            })
+            .collect()
+    }
+
+    fn variable(name: &str, path: Option<&str>) -> Variable {
+        Variable {
+            name: name.to_string(),
+            path: path.map(String::from),
+            escape: true,
+        }
+    }
+
+    fn placeholders(template: &str) -> Vec<Vec<String>> {
+        parse(template)
+            .unwrap()
+            .iter()
+            .filter_map(|node| match node {
+                Node::Placeholder(placeholder) => Some(alternatives(placeholder)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_text_and_placeholders() {
+        let nodes = parse("Hi %env:NAME%, 100% sure %env:A%%env:B%").unwrap();
+
+        assert_eq!(5, nodes.len());
+        assert_eq!(Node::Text("Hi ".into()), nodes[0]);
+        assert!(matches!(&nodes[1], Node::Placeholder(p) if p.raw == "%env:NAME%"));
+        assert_eq!(Node::Text(", 100% sure ".into()), nodes[2]);
+        assert!(matches!(&nodes[4], Node::Placeholder(p) if p.raw == "%env:B%"));
+
+        assert_eq!(
+            vec![Node::Text("%env:% 50%".into())],
+            parse("%env:% 50%").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_alternatives() {
+        assert_eq!(vec![vec!["env:A B"]], placeholders("%env:A B%"));
+        assert_eq!(
+            vec![vec!["env:DB_HOST", "awsssm:DB_HOST"]],
+            placeholders("%env,awsssm:DB_HOST%")
+        );
+        assert_eq!(
+            vec![vec!["env:DB_HOST", "awsssm:/app/db/host"]],
+            placeholders("%env:DB_HOST || awsssm:/app/db/host%")
+        );
+        assert_eq!(
+            vec![vec!["env:a||b", "custom:c"]],
+            placeholders("%env:a||b||custom:c%")
+        );
+
+        let optional = |template| match &parse(template).unwrap()[0] {
+            Node::Placeholder(placeholder) => placeholder.optional,
+            _ => panic!("Expected a placeholder"),
+        };
+        assert!(!optional("%env,awsssm:DB_HOST%"));
+        assert!(optional("%env,awsssm?:DB_HOST%"));
+    }
+
+    #[test]
+    fn test_parse_nested() {
+        assert_eq!(
+            vec![vec!["awsssm:/app/[env:STAGE]/db/host"]],
+            placeholders("%awsssm:/app/%env:STAGE%/db/host%")
+        );
+        assert_eq!(
+            vec![vec!["env:HOST", "awsssm:/[awsec2tag:Environment]/host"]],
+            placeholders("%env:HOST || awsssm:/%awsec2tag:Environment%/host%")
+        );
+
+        // A % which looks like it starts a nested placeholder but is never closed ends the
+        // placeholder instead
+        assert_eq!(
+            vec![vec!["env:NAME"], vec!["env:B"]],
+            placeholders("%env:NAME%abc:%env:B%")
+        );
+    }
+
+    #[test]
+    fn test_parse_matches_baseline_without_nesting() {
+        // The pattern placeholders were found with before nesting was supported
+        let baseline = regex::Regex::new(r"%([a-z0-9]+):([^%]+)%").unwrap();
+
+        for template in [
+            "%env:A%x:%env:B% 100%",
+            "%env:A%x:%env:B%",
+            "100%env:A% %env:B%",
+            "%env:A%%env:B%",
+            "50% off: %env:A% and 100%",
+            "%env:A%db:%env:B% and %env:C%",
+            "%env:A% 100%x:y %env:B%",
+            "a%b:c%d:e%f",
+            "%Env:A% %env-x:B% %env:C%",
+            "https://example.com/%7Euser:%env:A%",
+        ] {
+            let expected = baseline
+                .captures_iter(template)
+                .map(|captures| vec![format!("{}:{}", &captures[1], &captures[2])])
+                .collect::<Vec<_>>();
+
+            assert_eq!(expected, placeholders(template), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let mut template = String::from("X");
+        for _ in 0..MAX_DEPTH {
+            template = format!("%env:{}%", template);
+        }
+        assert!(parse(&template).is_ok());
+
+        let template = format!("%env:{}%", template);
+        assert!(parse(&template).is_err());
+    }
+
+    fn condition(tag: &str) -> Condition {
+        match parse(&format!("{}x%#end%", tag)).unwrap().remove(0) {
+            Node::Conditional { mut branches, .. } => branches.remove(0).0,
+            _ => panic!("Expected a conditional"),
+        }
+    }
+
+    fn subject(condition: &Condition) -> &Placeholder {
+        match &condition.subject {
+            Subject::Placeholder(placeholder) => placeholder,
+            Subject::Variable(_) => panic!("Expected a placeholder"),
+        }
+    }
+
+    #[test]
+    fn test_parse_conditions() {
+        let tls = condition("%#if env:TLS_ENABLED == true%");
+        assert_eq!(vec!["env:TLS_ENABLED"], alternatives(subject(&tls)));
+        assert_eq!(Some(Comparison::Equal("true".into())), tls.comparison);
+        assert!(!tls.negate);
+
+        let debug = condition("%#if !env,custom:DEBUG%");
+        assert_eq!(
+            vec!["env:DEBUG", "custom:DEBUG"],
+            alternatives(subject(&debug))
+        );
+        assert_eq!(None, debug.comparison);
+        assert!(debug.negate);
+
+        let stage = condition("%#if env,custom:STAGE != prod%");
+        assert_eq!(
+            vec!["env:STAGE", "custom:STAGE"],
+            alternatives(subject(&stage))
+        );
+        assert_eq!(Some(Comparison::NotEqual("prod".into())), stage.comparison);
+
+        assert!(parse("%#if nope%x%#end%").is_err());
+
+        let first = match parse("%#for i, x in env:A%%#if $i == 0%first%#end%%#end%").unwrap()[0] {
+            Node::Loop { ref body, .. } => match &body[0] {
+                Node::Conditional { branches, .. } => branches[0].0.clone(),
+                _ => panic!("Expected a conditional"),
+            },
+            _ => panic!("Expected a loop"),
+        };
+        assert_eq!(Subject::Variable(variable("i", None)), first.subject);
+        assert_eq!(Some(Comparison::Equal("0".into())), first.comparison);
+    }
+
+    #[test]
+    fn test_condition_evaluate() {
+        let truthy = condition("%#if env:A%");
+        assert!(truthy.evaluate(Some("yes")));
+        assert!(!truthy.evaluate(Some("false")));
+        assert!(!truthy.evaluate(Some("0")));
+        assert!(!truthy.evaluate(Some("")));
+        assert!(!truthy.evaluate(None));
+
+        let not_prod = condition("%#if env:A != prod%");
+        assert!(not_prod.evaluate(Some("dev")));
+        assert!(not_prod.evaluate(None));
+        assert!(!not_prod.evaluate(Some("prod")));
+    }
+
+    #[test]
+    fn test_parse_conditional_blocks() {
+        let nodes = parse("a\n  %#if env:A%\nb\n%#elif env:B% c\n%#else%\nd\n%#end%\ne").unwrap();
+
+        assert_eq!(3, nodes.len());
+        assert_eq!(Node::Text("a\n".into()), nodes[0]);
+        assert_eq!(Node::Text("e".into()), nodes[2]);
+
+        match &nodes[1] {
+            Node::Conditional {
+                branches,
+                otherwise,
+            } => {
+                // Tags on their own line are removed along with the line
+                assert_eq!(vec![Node::Text("b\n".into())], branches[0].1);
+                assert_eq!(vec![Node::Text(" c\n".into())], branches[1].1);
+                assert_eq!(vec![Node::Text("d\n".into())], *otherwise);
+            }
+            _ => panic!("Expected a conditional"),
+        }
+
+        assert!(parse("%#if env:A%").is_err());
+        assert!(parse("%#end%").is_err());
+        assert!(parse("%#if env:A%%#else%%#elif env:B%%#end%").is_err());
+    }
+
+    #[test]
+    fn test_parse_loops() {
+        let nodes =
+            parse("%#for i, host in env:BACKENDS%\n%$i%=%$host% %custom:/%$host%/port%\n%#end%\n")
+                .unwrap();
+
+        assert_eq!(1, nodes.len());
+        match &nodes[0] {
+            Node::Loop {
+                index,
+                item,
+                placeholder,
+                body,
+            } => {
+                assert_eq!(Some("i".to_string()), *index);
+                assert_eq!("host", item);
+                assert_eq!(vec!["env:BACKENDS"], alternatives(placeholder));
+                assert_eq!(Node::Variable(variable("i", None)), body[0]);
+                assert_eq!(Node::Variable(variable("host", None)), body[2]);
+                assert!(matches!(&body[4], Node::Placeholder(p) if p.uses_variables()));
+            }
+            _ => panic!("Expected a loop"),
+        }
+
+        assert!(parse("%#if $host%%#end%").is_err());
+        assert!(parse("%#for host env:A%%#end%").is_err());
+        assert!(parse("%#for host in env:A%%#else%%#end%").is_err());
+    }
+
+    #[test]
+    fn test_parse_variables_out_of_scope() {
+        // Variables which no loop defines are text, as they were before loops
+        let text = "cost 100%$HOME% here";
+        assert_eq!(vec![Node::Text(text.to_string())], parse(text).unwrap());
+
+        match &parse("%#for host in env:A%%$other%%$host%%#end%").unwrap()[0] {
+            Node::Loop { body, .. } => {
+                assert_eq!(Node::Text("%$other%".to_string()), body[0]);
+                assert_eq!(Node::Variable(variable("host", None)), body[1]);
+            }
+            _ => panic!("Expected a loop"),
+        }
+
+        // Variables go out of scope at the end of their loop
+        let nodes = parse("%#for host in env:A%%#end%%$host%").unwrap();
+        assert_eq!(Node::Text("%$host%".to_string()), nodes[1]);
+
+        // A placeholder ends at a % which starts a variable out of scope
+        assert_eq!(vec![vec!["env:A_"]], placeholders("%env:A_%$x%"));
+    }
+
+    fn complete_len(template: &str) -> usize {
+        Splitter::default().complete_len(template)
+    }
+
+    #[test]
+    fn test_complete_len() {
+        assert_eq!(0, complete_len("no line break"));
+        assert_eq!(4, complete_len("one\ntwo"));
+        assert_eq!(8, complete_len("%env:A%\n%env:B"));
+
+        // Placeholders can't be split, even across a line break in their key
+        assert_eq!(2, complete_len("a\n%env:A\nB"));
+
+        // Blocks have to be complete
+        let template = "a\n%#if env:A%\nb\n%#end%\nc\n%#for x in env:B%\n%$x%\n";
+        assert_eq!(template.find("%#for").unwrap(), complete_len(template));
+
+        // A tag which is cut off isn't complete
+        assert_eq!(2, complete_len("a\n%#if env:"));
+
+        // A % which can't start a placeholder doesn't stop the template being split
+        assert_eq!(7, complete_len("100% a\nb"));
+    }
+
+    #[test]
+    fn test_splitter_bounds_lines() {
+        // A stray % followed by a source is text once there's more after it than a placeholder
+        // can hold
+        let template = format!("a\n100%http://{}", "x".repeat(MAX_PLACEHOLDER_LEN));
+        let mut splitter = Splitter::default();
+        assert_eq!(template.len(), splitter.complete_len(&template));
+        assert_eq!(
+            vec![Node::Text(template.clone())],
+            parse(&template).unwrap()
+        );
+
+        // A long line is split before it's last placeholder, and the rest of it is scanned
+        // from where it was left
+        let mut template = format!("{}%env:A%", "x".repeat(MAX_PLACEHOLDER_LEN));
+        let mut splitter = Splitter::default();
+        assert_eq!(MAX_PLACEHOLDER_LEN, splitter.complete_len(&template));
+        splitter.split(MAX_PLACEHOLDER_LEN);
+        template.drain(..MAX_PLACEHOLDER_LEN);
+        template.push_str(" b %env:B");
+        assert_eq!(0, splitter.complete_len(&template));
+
+        // Blocks still have to be complete
+        let template = format!("%#if env:A%{}", "x".repeat(MAX_PLACEHOLDER_LEN + 1));
+        assert_eq!(0, complete_len(&template));
+    }
+
+    #[test]
+    fn test_parse_raw_placeholders() {
+        let nodes = parse("%env:A|raw% %env:B || env:C|raw% %env:D|b%").unwrap();
+
+        let placeholders = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Placeholder(placeholder) => Some(placeholder),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["env:A"], alternatives(placeholders[0]));
+        assert!(!placeholders[0].escape);
+        assert_eq!(vec!["env:B", "env:C"], alternatives(placeholders[1]));
+        assert!(!placeholders[1].escape);
+        assert_eq!(vec!["env:D|b"], alternatives(placeholders[2]));
+        assert!(placeholders[2].escape);
+
+        // A placeholder needs a key as well as the raw suffix
+        assert_eq!(
+            vec![Node::Text("%env:|raw%".into())],
+            parse("%env:|raw%").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_variable_paths() {
+        let nodes =
+            parse("%#for host in env:A%%$host#Tags.Name% %custom:%$host#Ip%%%#end%").unwrap();
+
+        match &nodes[0] {
+            Node::Loop { body, .. } => {
+                assert_eq!(Node::Variable(variable("host", Some("Tags.Name"))), body[0]);
+                match &body[2] {
+                    Node::Placeholder(placeholder) => {
+                        assert_eq!(vec!["custom:$host#Ip"], alternatives(placeholder))
+                    }
+                    _ => panic!("Expected a placeholder"),
+                }
+            }
+            _ => panic!("Expected a loop"),
+        }
+
+        let first = match parse("%#for x in env:A%%#if $x#On == true%on%#end%%#end%").unwrap()[0] {
+            Node::Loop { ref body, .. } => match &body[0] {
+                Node::Conditional { branches, .. } => branches[0].0.clone(),
+                _ => panic!("Expected a conditional"),
+            },
+            _ => panic!("Expected a loop"),
+        };
+        assert_eq!(Subject::Variable(variable("x", Some("On"))), first.subject);
+
+        // Variables can turn off escaping
+        match &parse("%#for x in env:A%%$x#Ip|raw%%#end%").unwrap()[0] {
+            Node::Loop { body, .. } => assert_eq!(
+                Node::Variable(Variable {
+                    escape: false,
+                    ..variable("x", Some("Ip"))
+                }),
+                body[0]
+            ),
+            _ => panic!("Expected a loop"),
+        }
+
+        // A # without a path isn't a variable
+        assert!(parse("%#for x in env:A%%$x#%%#end%").is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! The typed values loaders can return
+use crate::secret::MASK;
+use crate::{NotFound, Secret};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A value loaded from a source. Loaders which read structured data, such as JSON documents or
+/// SSM `StringList` parameters, can return it using
+/// [`Loader::load_value`](trait.Loader.html#method.load_value) so it keeps it's structure when
+/// selecting fields or looping over it in a template
+///
+/// When a value is written into a template it's formatted using [`expose`](#method.expose):
+/// strings are written as they are, lists are joined with commas, maps are written as compact JSON
+/// and bytes are base64 encoded. The `Display` implementation formats values the same way, except
+/// secrets are masked
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Value {
+    /// A string value
+    String(String),
+    /// A numeric value
+    Number(f64),
+    /// A boolean value
+    Bool(bool),
+    /// A list of values
+    List(Vec<Value>),
+    /// A map of values by their key
+    Map(BTreeMap<String, Value>),
+    /// Binary data
+    Bytes(Vec<u8>),
+    /// A sensitive string, which is masked everywhere other than the rendered template
+    Secret(Secret),
+}
+
+impl Value {
+    /// Selects the value at the given dot separated path, where numeric segments index into lists.
+    /// An empty path selects the whole value
+    ///
+    /// # Example
+    /// ```
+    /// use germinate::Value;
+    ///
+    /// let value = Value::from(serde_json::json!({"hosts": [{"ip": "10.0.0.1"}]}));
+    ///
+    /// assert_eq!(
+    ///     &Value::String("10.0.0.1".into()),
+    ///     value.select("hosts.0.ip").unwrap()
+    /// );
+    /// ```
+    pub fn select(&self, path: &str) -> Result<&Value> {
+        let mut current = self;
+
+        for segment in path.split('.').filter(|s| !s.is_empty()) {
+            current = match current {
+                Self::Map(map) => map.get(segment),
+                Self::List(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => None,
+            }
+            .ok_or_else(|| NotFound::new(format!("Path '{}' not found in value", path)))?;
+        }
+
+        Ok(current)
+    }
+
+    /// Converts the value into a list of values. Lists are returned as they are, strings are split
+    /// on commas and any other value is a list of one
+    pub fn into_list(self) -> Vec<Value> {
+        match self {
+            Self::List(items) => items,
+            Self::String(s) if s.trim().is_empty() => Vec::new(),
+            Self::String(s) => s
+                .split(',')
+                .map(|item| Self::String(item.trim().to_string()))
+                .collect(),
+            other => vec![other],
+        }
+    }
+
+    /// Formats the value as it's written into a template, including the values of any secrets.
+    /// The string returned is a plain copy, which isn't zeroed when it's dropped
+    ///
+    /// # Example
+    /// ```
+    /// use germinate::{Secret, Value};
+    ///
+    /// let value = Value::List(vec![Value::from("admin"), Value::Secret(Secret::new("hunter2"))]);
+    ///
+    /// assert_eq!("admin,hunter2", value.expose());
+    /// assert_eq!("admin,********", value.to_string());
+    /// ```
+    pub fn expose(&self) -> String {
+        struct Exposed<'v>(&'v Value);
+
+        impl fmt::Display for Exposed<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.format(f, true)
+            }
+        }
+
+        Exposed(self).to_string()
+    }
+
+    /// Whether the value is a secret or holds one
+    pub(crate) fn has_secret(&self) -> bool {
+        match self {
+            Self::Secret(_) => true,
+            Self::List(items) => items.iter().any(Value::has_secret),
+            Self::Map(map) => map.values().any(Value::has_secret),
+            _ => false,
+        }
+    }
+
+    /// Converts the value to JSON, as it's written into a template. Bytes are base64 encoded,
+    /// whole numbers are written without a fraction and numbers which can't be represented in JSON
+    /// become null
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        self.json(true)
+    }
+
+    /// Converts the value to JSON, masking any secrets unless `expose` is set
+    fn json(&self, expose: bool) -> serde_json::Value {
+        match self {
+            Self::String(s) => serde_json::Value::String(s.clone()),
+            Self::Secret(s) if expose => serde_json::Value::String(s.expose().to_string()),
+            Self::Secret(_) => serde_json::Value::String(MASK.to_string()),
+            Self::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                serde_json::Value::from(*n as i64)
+            }
+            Self::Number(n) => serde_json::Number::from_f64(*n)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Self::Bool(b) => serde_json::Value::Bool(*b),
+            Self::List(items) => {
+                serde_json::Value::Array(items.iter().map(|item| item.json(expose)).collect())
+            }
+            Self::Map(map) => serde_json::Value::Object(
+                map.iter()
+                    .map(|(k, v)| (k.clone(), v.json(expose)))
+                    .collect(),
+            ),
+            Self::Bytes(bytes) => serde_json::Value::String(base64::encode(bytes)),
+        }
+    }
+
+    /// Formats the value, masking any secrets unless `expose` is set
+    fn format(&self, f: &mut fmt::Formatter<'_>, expose: bool) -> fmt::Result {
+        match self {
+            Self::String(s) => f.write_str(s),
+            Self::Secret(s) if expose => f.write_str(s.expose()),
+            Self::Secret(s) => write!(f, "{}", s),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    item.format(f, expose)?;
+                }
+                Ok(())
+            }
+            Self::Map(_) => write!(f, "{}", self.json(expose)),
+            Self::Bytes(bytes) => f.write_str(&base64::encode(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Formats the value as it's written into a template, except secrets are masked
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format(f, false)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    /// Converts a JSON value, where null becomes an empty string
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Self::String(String::new()),
+            serde_json::Value::Bool(b) => Self::Bool(b),
+            serde_json::Value::Number(n) => Self::Number(n.as_f64().unwrap_or_default()),
+            serde_json::Value::String(s) => Self::String(s),
+            serde_json::Value::Array(items) => {
+                Self::List(items.into_iter().map(Self::from).collect())
+            }
+            serde_json::Value::Object(map) => {
+                Self::Map(map.into_iter().map(|(k, v)| (k, Self::from(v))).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select() {
+        let value = Value::from(json!({"a": {"b": [{"c": "value"}]}}));
+
+        assert_eq!(&Value::from("value"), value.select("a.b.0.c").unwrap());
+        assert_eq!(&value, value.select("").unwrap());
+
+        let err = value.select("a.missing").unwrap_err();
+        assert!(err.is::<NotFound>());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("text", Value::from("text").to_string());
+        assert_eq!(
+            "1,two,true,1.5",
+            Value::from(json!([1, "two", true, 1.5])).to_string()
+        );
+        assert_eq!(
+            r#"{"a":1,"b":[0.5]}"#,
+            Value::from(json!({"a": 1, "b": [0.5]})).to_string()
+        );
+        assert_eq!("", Value::from(json!(null)).to_string());
+        assert_eq!("aGk=", Value::Bytes(b"hi".to_vec()).to_string());
+    }
+
+    #[test]
+    fn test_secret() {
+        let mut map = BTreeMap::new();
+        map.insert("user".to_string(), Value::from("admin"));
+        map.insert(
+            "password".to_string(),
+            Value::Secret(Secret::new("hunter2")),
+        );
+        let value = Value::Map(map);
+
+        assert_eq!(
+            r#"{"password":"********","user":"admin"}"#,
+            value.to_string()
+        );
+        assert_eq!(r#"{"password":"hunter2","user":"admin"}"#, value.expose());
+        assert!(!format!("{:?}", value).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_into_list() {
+        assert_eq!(
+            vec![Value::from("a,b"), Value::from("c")],
+            Value::from(json!(["a,b", "c"])).into_list()
+        );
+        assert_eq!(
+            vec![Value::from("a"), Value::from("b")],
+            Value::from("a, b").into_list()
+        );
+        assert!(Value::from("").into_list().is_empty());
+        assert_eq!(vec![Value::Bool(true)], Value::Bool(true).into_list());
+    }
+}